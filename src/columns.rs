@@ -0,0 +1,78 @@
+use crate::line_breaker::str_width;
+use std::collections::HashMap;
+
+/// The number of lines looked at to detect the delimiter and the width of the columns.
+const SAMPLE_LINES: usize = 1000;
+
+/// Aligns the columns of tab- or comma-separated `input` by padding the fields with spaces. The
+/// width of each column is the widest field in the first lines. Returns `None` if the input doesn't
+/// look like a table, i.e. most lines don't have the same number of fields.
+pub fn align_columns(input: &str) -> Option<String> {
+    let delimiter = ['\t', ','].iter().copied().find(|&delimiter| {
+        let sample: Vec<_> = input
+            .lines()
+            .filter(|line| !line.is_empty())
+            .take(SAMPLE_LINES)
+            .collect();
+        let mut counts = HashMap::new();
+        for line in &sample {
+            *counts
+                .entry(split_fields(line, delimiter).len())
+                .or_insert(0) += 1;
+        }
+
+        // Allow a few odd lines such as a title or a summary.
+        counts
+            .iter()
+            .any(|(&fields, &lines)| fields >= 2 && lines * 10 >= sample.len() * 9)
+    })?;
+
+    let mut widths = vec![];
+    for line in input.lines().take(SAMPLE_LINES) {
+        for (column, field) in split_fields(line, delimiter).into_iter().enumerate() {
+            let width = str_width(field.trim());
+            match widths.get_mut(column) {
+                Some(max_width) if *max_width < width => *max_width = width,
+                Some(_) => {}
+                None => widths.push(width),
+            }
+        }
+    }
+
+    let mut aligned = String::with_capacity(input.len());
+    for line in input.lines() {
+        let fields = split_fields(line, delimiter);
+        let num_fields = fields.len();
+        for (column, field) in fields.into_iter().enumerate() {
+            let field = field.trim();
+            aligned.push_str(field);
+            if column + 1 < num_fields {
+                let width = widths.get(column).copied().unwrap_or(0);
+                let padding = width.saturating_sub(str_width(field)) + 2;
+                aligned.push_str(&" ".repeat(padding));
+            }
+        }
+        aligned.push('\n');
+    }
+
+    Some(aligned)
+}
+
+/// Splits `line` at `delimiter`. A delimiter between double quotes is part of the field, as in
+/// CSV.
+fn split_fields(line: &str, delimiter: char) -> Vec<&str> {
+    let mut fields = vec![];
+    let mut field_start = 0;
+    let mut in_quotes = false;
+    for (pos, ch) in line.char_indices() {
+        if ch == '"' {
+            in_quotes = !in_quotes;
+        } else if ch == delimiter && !in_quotes {
+            fields.push(&line[field_start..pos]);
+            field_start = pos + ch.len_utf8();
+        }
+    }
+    fields.push(&line[field_start..]);
+
+    fields
+}
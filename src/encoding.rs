@@ -0,0 +1,105 @@
+#[cfg(feature = "encoding")]
+use anyhow::anyhow;
+#[cfg(not(feature = "encoding"))]
+use anyhow::bail;
+#[cfg(feature = "encoding")]
+use encoding_rs::{Encoding, EUC_JP, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+/// The number of bytes looked at to tell UTF-16 without a BOM.
+#[cfg(feature = "encoding")]
+const SAMPLE_LEN: usize = 4096;
+
+/// Text with less than this percentage of bytes invalid in UTF-8 is still UTF-8, with a few stray
+/// bytes.
+#[cfg(feature = "encoding")]
+const MAX_STRAY_PERCENT: usize = 1;
+
+/// Decodes `bytes` in the encoding named `label`, e.g. `shift_jis` or `utf-16le`, or in the
+/// encoding guessed from the BOM and the bytes if `label` is `None`. Returns the text with the name
+/// of the encoding, or `None` if the bytes are UTF-8 or binary; such bytes are read as UTF-8, with
+/// invalid bytes replaced.
+#[cfg(feature = "encoding")]
+pub fn decode(bytes: &[u8], label: Option<&str>) -> anyhow::Result<Option<(String, &'static str)>> {
+    let encoding = match label {
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| anyhow!("unknown encoding `{}`", label))?,
+        None => match guess_encoding(bytes) {
+            Some(encoding) => encoding,
+            None => return Ok(None),
+        },
+    };
+    if encoding == UTF_8 {
+        return Ok(None);
+    }
+
+    let (text, encoding, _) = encoding.decode(bytes);
+    Ok(Some((text.into_owned(), encoding.name())))
+}
+
+#[cfg(not(feature = "encoding"))]
+pub fn decode(
+    _bytes: &[u8],
+    label: Option<&str>,
+) -> anyhow::Result<Option<(String, &'static str)>> {
+    match label {
+        Some(_) => bail!("pag is built without encoding support"),
+        None => Ok(None),
+    }
+}
+
+/// Guesses the encoding of `bytes`: by the BOM if any, then UTF-16 if every other byte of ASCII
+/// text is NUL, and Shift_JIS or EUC-JP if the bytes are valid in them. Other text is Latin-1
+/// (windows-1252). Returns `None` for binary bytes, which have NUL bytes otherwise.
+#[cfg(feature = "encoding")]
+fn guess_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return Some(encoding);
+    }
+
+    let sample = &bytes[..bytes.len().min(SAMPLE_LEN)];
+    let nuls_at = |parity| {
+        sample
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|&&byte| byte == 0)
+            .count()
+    };
+    let (even_nuls, odd_nuls) = (nuls_at(0), nuls_at(1));
+    let pairs = sample.len() / 2;
+    if pairs > 0 && even_nuls == 0 && odd_nuls * 2 >= pairs {
+        return Some(UTF_16LE);
+    }
+    if pairs > 0 && odd_nuls == 0 && even_nuls * 2 >= pairs {
+        return Some(UTF_16BE);
+    }
+    if even_nuls + odd_nuls > 0 {
+        return None;
+    }
+
+    // A few stray bytes in UTF-8 text, e.g. a broken line in a log, don't make it another
+    // encoding. They are few compared with the valid non-ASCII characters, or with the whole text
+    // if it is mostly ASCII.
+    let (mut valid, mut invalid) = (0, 0);
+    for chunk in bytes.utf8_chunks() {
+        valid += chunk
+            .valid()
+            .bytes()
+            .filter(|byte| !byte.is_ascii())
+            .count();
+        invalid += chunk.invalid().len();
+    }
+    if valid >= invalid || invalid * 100 < bytes.len() * MAX_STRAY_PERCENT {
+        return Some(UTF_8);
+    }
+
+    [SHIFT_JIS, EUC_JP]
+        .iter()
+        .copied()
+        .find(|encoding| {
+            encoding
+                .decode_without_bom_handling_and_without_replacement(bytes)
+                .is_some()
+        })
+        .or(Some(WINDOWS_1252))
+}
@@ -0,0 +1,44 @@
+use std::fmt::Write;
+
+/// The number of bytes shown on each line of a hex dump.
+pub const BYTES_PER_LINE: usize = 16;
+
+/// Returns whether `bytes` look like a binary file rather than text, i.e. they have a NUL byte.
+/// Bytes which aren't valid UTF-8 are not enough; a few of them may be broken in a long log.
+pub fn looks_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
+}
+
+/// Formats `bytes` like `xxd`: the offset, the bytes in hex in groups of two and the printable
+/// ASCII characters, with `.` for the others.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    hex_dump_at(bytes, 0)
+}
+
+/// Formats `bytes` like `hex_dump()` as if they were at `offset` in the input, to continue a dump.
+pub(crate) fn hex_dump_at(bytes: &[u8], offset: usize) -> String {
+    let mut dump = String::with_capacity(bytes.len() / BYTES_PER_LINE * 68 + 68);
+    for (idx, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        write!(dump, "{:08x}:", offset + idx * BYTES_PER_LINE).unwrap();
+        for pos in 0..BYTES_PER_LINE {
+            if pos % 2 == 0 {
+                dump.push(' ');
+            }
+            match chunk.get(pos) {
+                Some(byte) => write!(dump, "{:02x}", byte).unwrap(),
+                None => dump.push_str("  "),
+            }
+        }
+        dump.push_str("  ");
+        dump.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('\n');
+    }
+
+    dump
+}
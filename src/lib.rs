@@ -0,0 +1,16 @@
+//! The pager behind `pag`: wrapping the contents to the terminal width, scrolling, searching and
+//! drawing the screen. The `pag` binary feeds `Screen` with crossterm events.
+
+mod columns;
+mod encoding;
+mod hex_dump;
+mod line_breaker;
+mod line_edit;
+mod screen;
+
+pub use columns::align_columns;
+pub use encoding::decode;
+pub use hex_dump::{hex_dump, looks_binary};
+pub use line_breaker::{CrMode, LineBreaker, WrapMode};
+pub use line_edit::LineEdit;
+pub use screen::{CaseMode, LineNumbers, MoveUnit, Screen};
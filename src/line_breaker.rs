@@ -0,0 +1,883 @@
+use anyhow::anyhow;
+use std::borrow::Cow;
+use std::cmp::min;
+use std::iter::{from_fn, repeat_n};
+use std::ops::Range;
+use std::str::FromStr;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+/// How carriage returns (`\r`) in the input are treated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CrMode {
+    /// Carriage returns are dropped, so both `\n` and `\r\n` end a line.
+    Strip,
+    /// A lone `\r` ends a line as well as `\n` and `\r\n`, like old Mac files.
+    Break,
+    /// Text after a `\r` overwrites the line from its beginning, as a terminal shows progress
+    /// bars.
+    Overwrite,
+}
+
+impl FromStr for CrMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(CrMode::Strip),
+            "break" => Ok(CrMode::Break),
+            "overwrite" => Ok(CrMode::Overwrite),
+            _ => Err(anyhow!(
+                "unknown carriage return mode `{}` (expected strip, break or overwrite)",
+                s
+            )),
+        }
+    }
+}
+
+/// Where long lines are broken.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WrapMode {
+    /// Break at the character which would exceed the width.
+    Char,
+    /// Break after the last whitespace that fits, so words are not split. A word longer than the
+    /// width is broken after a punctuation such as `/` or `-` in it, e.g. in a path or a URL, or
+    /// else at a character.
+    Word,
+    /// Lines are not wrapped at all. The part beyond the screen is seen by scrolling horizontally.
+    None,
+}
+
+impl FromStr for WrapMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "char" => Ok(WrapMode::Char),
+            "word" => Ok(WrapMode::Word),
+            "none" => Ok(WrapMode::None),
+            _ => Err(anyhow!(
+                "unknown wrap mode `{}` (expected char, word or none)",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns the length of the first source line in `rest` and the length of its line terminator,
+/// which is zero if the line is not terminated yet.
+fn split_source_line(rest: &str, cr_mode: CrMode) -> (usize, usize) {
+    let bytes = rest.as_bytes();
+    for (idx, &byte) in bytes.iter().enumerate() {
+        match byte {
+            b'\n' => return (idx, 1),
+            b'\r' if cr_mode == CrMode::Break => match bytes.get(idx + 1) {
+                Some(b'\n') => return (idx, 2),
+                Some(_) => return (idx, 1),
+                // `\n` may follow later, so a trailing `\r` doesn't end the line yet.
+                None => {}
+            },
+            _ => {}
+        }
+    }
+
+    (rest.len(), 0)
+}
+
+/// Returns the source lines of `contents` one by one, without their line terminators.
+pub(crate) fn source_line_texts(contents: &str, cr_mode: CrMode) -> impl Iterator<Item = &str> {
+    let mut rest = Some(contents);
+    from_fn(move || {
+        let text = rest?;
+        let (len, terminator_len) = split_source_line(text, cr_mode);
+        rest = Some(&text[len + terminator_len..]).filter(|_| terminator_len > 0);
+        Some(&text[..len])
+    })
+}
+
+/// Returns the (0-based) source line containing the byte `offset` of `contents`, and the byte
+/// offset where that line starts.
+pub(crate) fn source_line_at(contents: &str, offset: usize, cr_mode: CrMode) -> (usize, usize) {
+    let mut source_line = 0;
+    let mut line_start = 0;
+    loop {
+        let (len, terminator_len) = split_source_line(&contents[line_start..], cr_mode);
+        let next_line_start = line_start + len + terminator_len;
+        if terminator_len == 0 || next_line_start > offset {
+            return (source_line, line_start);
+        }
+        source_line += 1;
+        line_start = next_line_start;
+    }
+}
+
+/// Applies carriage returns in `line` the way a terminal does: each `\r` moves back to the
+/// beginning of the line and the following text overwrites what was there. Every character is
+/// assumed to occupy one cell, which is good enough for progress bars.
+fn overwrite_carriage_returns(line: &str) -> String {
+    let mut cells = vec![];
+    let mut cursor = 0;
+    for ch in line.chars() {
+        if ch == '\r' {
+            cursor = 0;
+            continue;
+        }
+
+        if cursor < cells.len() {
+            cells[cursor] = ch;
+        } else {
+            cells.push(ch);
+        }
+        cursor += 1;
+    }
+
+    cells.into_iter().collect()
+}
+
+/// Returns the caret notation of a control character, e.g. `^M` for a carriage return. Control
+/// characters outside of ASCII are shown as their code point such as `<U+009B>`.
+pub(crate) fn caret_notation(ch: char) -> String {
+    match ch {
+        '\x00'..='\x1f' => format!("^{}", (ch as u8 + b'@') as char),
+        '\x7f' => "^?".to_string(),
+        _ => format!("<U+{:04X}>", ch as u32),
+    }
+}
+
+/// The OSC 8 sequence which ends a hyperlink.
+const HYPERLINK_END: &str = "\x1b]8;;\x1b\\";
+
+/// Returns the length of the OSC 8 hyperlink sequence at the beginning of `s`, e.g.
+/// `\x1b]8;;https://example.com\x1b\\`, which is terminated by ST (`\x1b\\`) or BEL.
+fn hyperlink_len(s: &str) -> Option<usize> {
+    let body = s.strip_prefix("\x1b]8;")?;
+    let end = body.find(['\x07', '\x1b'])?;
+    let terminator_len = if body[end..].starts_with('\x07') {
+        1
+    } else if body[end..].starts_with("\x1b\\") {
+        2
+    } else {
+        return None;
+    };
+
+    Some(s.len() - body.len() + end + terminator_len)
+}
+
+/// Returns the length of the CSI escape sequence at the beginning of `s`, e.g. `\x1b[1;31m` which
+/// makes the text bold and red.
+fn csi_len(s: &str) -> Option<usize> {
+    let body = s.strip_prefix("\x1b[")?;
+    let params_len = body
+        .find(|ch| !('\x20'..='\x3f').contains(&ch))
+        .unwrap_or(body.len());
+    let final_byte = body[params_len..].chars().next()?;
+    if !('\x40'..='\x7e').contains(&final_byte) {
+        return None;
+    }
+
+    Some("\x1b[".len() + params_len + 1)
+}
+
+/// Returns whether `unit` from `display_units()` is a CSI escape sequence.
+pub(crate) fn is_csi(unit: &str) -> bool {
+    unit.starts_with("\x1b[")
+}
+
+/// Returns whether `unit` from `display_units()` is an SGR sequence, which sets the colors and the
+/// attributes of the text after it.
+pub(crate) fn is_sgr(unit: &str) -> bool {
+    is_csi(unit) && unit.ends_with('m')
+}
+
+/// Returns whether `unit` from `display_units()` is an OSC 8 hyperlink sequence.
+pub(crate) fn is_hyperlink(unit: &str) -> bool {
+    unit.starts_with("\x1b]8;")
+}
+
+/// Returns whether the OSC 8 sequence `unit` starts a link rather than ends one. The sequence is
+/// `ESC ] 8 ; params ; URI ST`, where an empty URI ends the link.
+fn opens_hyperlink(unit: &str) -> bool {
+    let body = unit
+        .strip_suffix("\x1b\\")
+        .or_else(|| unit.strip_suffix('\x07'))
+        .unwrap_or(unit);
+    body["\x1b]8;".len()..]
+        .split_once(';')
+        .is_some_and(|(_, uri)| !uri.is_empty())
+}
+
+/// Splits `s` into grapheme clusters. If `sequences` is set, an escape sequence such as an OSC 8
+/// hyperlink or a color (SGR) is kept together as one unit rather than split into the escape
+/// character and the rest, and so is a character overstruck with backspaces like `X\bX` (bold) or
+/// `_\bX` (underlined) in man pages.
+pub(crate) fn display_units(s: &str, sequences: bool) -> DisplayUnits<'_> {
+    DisplayUnits { rest: s, sequences }
+}
+
+/// The iterator returned by `display_units()`.
+pub(crate) struct DisplayUnits<'a> {
+    rest: &'a str,
+    sequences: bool,
+}
+
+impl<'a> Iterator for DisplayUnits<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sequence_len = hyperlink_len(self.rest).or_else(|| csi_len(self.rest));
+        let mut len = match sequence_len.filter(|_| self.sequences) {
+            Some(len) => len,
+            None => self.rest.graphemes(true).next()?.len(),
+        };
+        // Each backspace overstrikes the cluster before it with the one after it.
+        while self.sequences && !self.rest.starts_with(['\x08', '\x1b']) {
+            let overstruck = match self.rest[len..].strip_prefix('\x08') {
+                Some(rest) => rest.graphemes(true).next(),
+                None => None,
+            };
+            match overstruck {
+                Some(cluster) if !cluster.starts_with(['\x08', '\n']) => len += 1 + cluster.len(),
+                _ => break,
+            }
+        }
+        let (unit, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Some(unit)
+    }
+}
+
+/// Returns how a unit from `display_units()` made of overstruck characters looks: the character
+/// shown, and whether it is bold and underlined. `None` if `unit` is not overstruck.
+pub(crate) fn overstrike(unit: &str) -> Option<(&str, bool, bool)> {
+    if unit.len() < 3 || !unit.contains('\x08') {
+        return None;
+    }
+
+    let mut parts = unit.rsplit('\x08');
+    let shown = parts.next()?;
+    let mut bold = false;
+    let mut underline = false;
+    for part in parts {
+        if part == shown {
+            bold = true;
+        } else if part == "_" {
+            underline = true;
+        }
+    }
+
+    Some((shown, bold, underline))
+}
+
+/// Returns the text of `s` as it is shown, without the escape sequences and with only the last
+/// character of each overstruck unit, e.g. to search it. Each byte of the text comes with the byte
+/// offset in `s` of the unit it is shown from, and the offsets end with `s.len()`.
+pub(crate) fn shown_text(s: &str) -> (String, Vec<usize>) {
+    let mut text = String::with_capacity(s.len());
+    let mut offsets = Vec::with_capacity(s.len() + 1);
+    for unit in display_units(s, true) {
+        let start = unit.as_ptr() as usize - s.as_ptr() as usize;
+        let shown = if is_csi(unit) || is_hyperlink(unit) {
+            ""
+        } else {
+            overstrike(unit).map_or(unit, |(shown, _, _)| shown)
+        };
+        text.push_str(shown);
+        offsets.extend(repeat_n(start, shown.len()));
+    }
+    offsets.push(s.len());
+
+    (text, offsets)
+}
+
+/// Returns the byte ranges of the CSI escape sequences in `s`.
+pub(crate) fn csi_ranges(s: &str) -> Vec<Range<usize>> {
+    display_units(s, true)
+        .filter(|unit| is_csi(unit))
+        .map(|unit| {
+            let start = unit.as_ptr() as usize - s.as_ptr() as usize;
+            start..start + unit.len()
+        })
+        .collect()
+}
+
+/// Returns the byte ranges of the overstruck units in `s`, with where the shown character starts
+/// and whether it is bold and underlined.
+pub(crate) fn overstrike_ranges(s: &str) -> Vec<(Range<usize>, usize, bool, bool)> {
+    display_units(s, true)
+        .filter_map(|unit| {
+            let (shown, bold, underline) = overstrike(unit)?;
+            let start = unit.as_ptr() as usize - s.as_ptr() as usize;
+            Some((
+                start..start + unit.len(),
+                start + unit.len() - shown.len(),
+                bold,
+                underline,
+            ))
+        })
+        .collect()
+}
+
+/// Returns the byte ranges of the OSC 8 hyperlink sequences in `s`.
+pub(crate) fn hyperlink_ranges(s: &str) -> Vec<Range<usize>> {
+    display_units(s, true)
+        .filter(|unit| is_hyperlink(unit))
+        .map(|unit| {
+            let start = unit.as_ptr() as usize - s.as_ptr() as usize;
+            start..start + unit.len()
+        })
+        .collect()
+}
+
+/// Returns the number of columns a grapheme cluster occupies on the terminal. Terminals don't agree
+/// on complex clusters, so this is a best effort: most of them draw a cluster as wide as its first
+/// character, e.g. a family emoji joined with ZWJs takes two columns rather than the sum of its
+/// members, except that an emoji presentation selector or a pair of regional indicators (a flag)
+/// makes it two columns wide.
+pub(crate) fn cluster_width(cluster: &str) -> usize {
+    if is_hyperlink(cluster) || is_csi(cluster) {
+        // Only the text of a link is shown, and a color doesn't take a column by itself.
+        return 0;
+    }
+    if let Some((shown, _, _)) = overstrike(cluster) {
+        return cluster_width(shown);
+    }
+
+    let first = match cluster.chars().next() {
+        Some(first) => first,
+        None => return 0,
+    };
+    let is_regional_indicator = |ch: char| ('\u{1f1e6}'..='\u{1f1ff}').contains(&ch);
+
+    let width = first.width().unwrap_or(1);
+    if cluster.contains('\u{fe0f}') || (is_regional_indicator(first) && cluster.chars().count() > 1)
+    {
+        width.max(2)
+    } else {
+        width
+    }
+}
+
+/// The distance between tab stops unless `--tabs` is given.
+pub(crate) const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// The punctuations after which `WrapMode::Word` can break a word longer than the line.
+const BREAK_AFTER: &[&str] = &["/", "\\", "-", "_", ",", ";", ".", "&", "?", "="];
+
+/// Returns the number of columns a display unit at `column` takes when nonprinting characters or
+/// whitespace may be shown as markers. A tab reaches to the next tab stop, every `tab_width`
+/// columns, unless it is shown as `^I`. Other control characters are shown in the caret notation
+/// unless `raw_control_chars` is set.
+pub(crate) fn marked_width(
+    unit: &str,
+    column: usize,
+    tab_width: usize,
+    show_nonprinting: bool,
+    show_whitespace: bool,
+    raw_control_chars: bool,
+) -> usize {
+    let is_sequence = is_csi(unit) || is_hyperlink(unit);
+    match unit.chars().next() {
+        Some('\t') if show_whitespace || !show_nonprinting => tab_width - column % tab_width,
+        Some(ch) if (show_nonprinting || !raw_control_chars) && ch.is_control() && !is_sequence => {
+            unit.chars().map(|ch| caret_notation(ch).len()).sum()
+        }
+        _ => cluster_width(unit),
+    }
+}
+
+/// Returns the number of columns `s` occupies on the terminal.
+pub(crate) fn str_width(s: &str) -> usize {
+    display_units(s, true).map(cluster_width).sum()
+}
+
+/// Splits the contents into lines which fit in the given width.
+pub struct LineBreaker<'a> {
+    contents: &'a str,
+    /// Byte offset in `contents` where the source line currently being wrapped starts.
+    line_start: usize,
+    /// The source line being wrapped, with its carriage returns already processed. `None` if the
+    /// next source line hasn't been read yet.
+    line: Option<Cow<'a, str>>,
+    /// Byte offset in `line` where the next wrapped line starts.
+    line_pos: usize,
+    /// Byte offset in `contents` where the next source line starts, or `None` if the current one
+    /// is not terminated, i.e. it is the last line.
+    next_line_start: Option<usize>,
+    source_line: usize,
+    /// The number of wrapped lines made from the current source line so far.
+    rows: usize,
+    /// The OSC 8 sequence of the hyperlink which continues from the previous wrapped line.
+    open_hyperlink: Option<String>,
+    /// The SGR sequences since the last reset, i.e. the colors which continue from the previous
+    /// wrapped line.
+    open_colors: String,
+    width: usize,
+    tab_width: usize,
+    show_nonprinting: bool,
+    show_whitespace: bool,
+    raw_control_chars: bool,
+    cr_mode: CrMode,
+    wrap_mode: WrapMode,
+    max_rows: Option<usize>,
+    /// The number of columns left for the marker at the beginning of each continued row.
+    wrap_marker_width: usize,
+    /// Whether continued rows are indented as much as the beginning of the source line.
+    keep_indent: bool,
+    /// The width of the leading whitespace of the current source line.
+    indent: usize,
+    /// The number of columns left empty at the beginning of the last wrapped line.
+    row_indent: usize,
+}
+
+impl<'a> LineBreaker<'a> {
+    pub fn new(width: usize, contents: &'a str) -> Self {
+        Self {
+            contents,
+            line_start: 0,
+            line: None,
+            line_pos: 0,
+            next_line_start: Some(0),
+            source_line: 0,
+            rows: 0,
+            open_hyperlink: None,
+            open_colors: String::new(),
+            width,
+            tab_width: DEFAULT_TAB_WIDTH,
+            show_nonprinting: false,
+            show_whitespace: false,
+            raw_control_chars: false,
+            cr_mode: CrMode::Strip,
+            wrap_mode: WrapMode::Char,
+            max_rows: None,
+            wrap_marker_width: 0,
+            keep_indent: false,
+            indent: 0,
+            row_indent: 0,
+        }
+    }
+
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// Makes the width of control characters match their caret notation.
+    pub fn set_show_nonprinting(&mut self, show_nonprinting: bool) {
+        self.show_nonprinting = show_nonprinting;
+    }
+
+    /// Makes tabs take their width even when control characters are shown, and leaves room for the
+    /// `$` at the end of each source line.
+    pub fn set_show_whitespace(&mut self, show_whitespace: bool) {
+        self.show_whitespace = show_whitespace;
+    }
+
+    /// Makes control characters take no width of their own, as they are printed as they are.
+    pub fn set_raw_control_chars(&mut self, raw_control_chars: bool) {
+        self.raw_control_chars = raw_control_chars;
+    }
+
+    pub fn set_cr_mode(&mut self, cr_mode: CrMode) {
+        self.cr_mode = cr_mode;
+    }
+
+    pub fn set_wrap_mode(&mut self, wrap_mode: WrapMode) {
+        self.wrap_mode = wrap_mode;
+    }
+
+    /// Limits how many wrapped lines a single source line makes. The last of them ends with `…`
+    /// and the rest of the source line is not shown.
+    pub fn set_max_rows(&mut self, max_rows: Option<usize>) {
+        self.max_rows = max_rows;
+    }
+
+    /// Leaves `width` columns at the beginning of each continued row for a marker.
+    pub fn set_wrap_marker_width(&mut self, width: usize) {
+        self.wrap_marker_width = width;
+    }
+
+    /// Makes the continued rows indented as much as the beginning of their source line, so that
+    /// wrapped code or YAML keeps its shape.
+    pub fn set_keep_indent(&mut self, keep_indent: bool) {
+        self.keep_indent = keep_indent;
+    }
+
+    /// Returns the number of columns to leave empty, or to fill with the marker, before the wrapped
+    /// line last returned. It is zero for the first row of a source line.
+    pub fn row_indent(&self) -> usize {
+        self.row_indent
+    }
+
+    /// Returns the (0-based) source line which the next wrapped line belongs to.
+    pub fn source_line(&self) -> usize {
+        self.source_line
+    }
+
+    /// Returns the byte offset where the source line `source_line()` starts.
+    pub fn line_start(&self) -> usize {
+        self.line_start
+    }
+
+    /// Returns whether the next wrapped line starts a new source line.
+    pub fn is_at_line_boundary(&self) -> bool {
+        self.line.is_none()
+    }
+
+    /// Reads the next source line. Returns `false` at the end of the contents.
+    fn read_source_line(&mut self) -> bool {
+        let line_start = match self.next_line_start {
+            Some(next_line_start) => next_line_start,
+            None => return false,
+        };
+        let rest = &self.contents[line_start..];
+        let (len, terminator_len) = split_source_line(rest, self.cr_mode);
+        let line = &rest[..len];
+
+        // The last line without contents doesn't make a wrapped line, e.g. after the final `\n`.
+        if terminator_len == 0 && line.trim_matches('\r').is_empty() {
+            return false;
+        }
+
+        let line = match self.cr_mode {
+            // Only a trailing `\r` can be left here; it's waiting for `\n`.
+            CrMode::Break => Cow::Borrowed(line.trim_end_matches('\r')),
+            _ if self.show_nonprinting || !line.contains('\r') => Cow::Borrowed(line),
+            CrMode::Strip => Cow::Owned(line.replace('\r', "")),
+            CrMode::Overwrite => Cow::Owned(overwrite_carriage_returns(line)),
+        };
+
+        self.indent = if self.keep_indent {
+            self.leading_whitespace_width(&line)
+        } else {
+            0
+        };
+        self.line_start = line_start;
+        self.line = Some(line);
+        self.line_pos = 0;
+        self.rows = 0;
+        self.open_hyperlink = None;
+        self.open_colors.clear();
+        self.next_line_start =
+            Some(line_start + len + terminator_len).filter(|_| terminator_len > 0);
+
+        true
+    }
+
+    /// Returns the number of columns the whitespace at the beginning of `line` takes.
+    fn leading_whitespace_width(&self, line: &str) -> usize {
+        display_units(line, !self.show_nonprinting)
+            .take_while(|unit| is_sgr(unit) || unit.chars().all(char::is_whitespace))
+            .fold(0, |column, unit| column + self.display_width(unit, column))
+    }
+
+    /// Returns the number of columns `cluster` takes on the screen at `column`.
+    fn display_width(&self, cluster: &str, column: usize) -> usize {
+        marked_width(
+            cluster,
+            column,
+            self.tab_width,
+            self.show_nonprinting,
+            self.show_whitespace,
+            self.raw_control_chars,
+        )
+    }
+
+    /// Returns the number of columns a wrapped line takes on the screen.
+    fn row_width(&self, row: &str) -> usize {
+        display_units(row, !self.show_nonprinting)
+            .fold(0, |column, unit| column + self.display_width(unit, column))
+    }
+
+    /// Ends the hyperlink left open at the end of `row`, so that it doesn't spill over whatever is
+    /// drawn next, and remembers the colors in effect. Both are opened again at the beginning of
+    /// the next row.
+    fn close_sequences(&mut self, row: &mut String) {
+        if self.show_nonprinting {
+            return;
+        }
+
+        // `row` starts with the colors from the previous row, so they are collected again.
+        self.open_colors.clear();
+        for unit in display_units(row, true).filter(|unit| is_sgr(unit)) {
+            if matches!(unit, "\x1b[m" | "\x1b[0m") {
+                self.open_colors.clear();
+            } else {
+                self.open_colors.push_str(unit);
+            }
+        }
+
+        if let Some(last) = display_units(row, true)
+            .filter(|unit| is_hyperlink(unit))
+            .last()
+        {
+            self.open_hyperlink = Some(last.to_string()).filter(|_| opens_hyperlink(last));
+        }
+        if self.open_hyperlink.is_some() {
+            row.push_str(HYPERLINK_END);
+        }
+    }
+
+    /// Moves on to the next source line after the current one has been wrapped.
+    fn finish_source_line(&mut self) {
+        self.line = None;
+        if let Some(next_line_start) = self.next_line_start {
+            self.line_start = next_line_start;
+            self.source_line += 1;
+        }
+    }
+}
+
+impl Iterator for LineBreaker<'_> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.line.is_none() && !self.read_source_line() {
+            return None;
+        }
+
+        let line = self.line.as_ref().unwrap();
+        self.rows += 1;
+        // Continued rows start after the marker and the indentation, but at least half of the
+        // width is kept for the contents.
+        self.row_indent = if self.rows > 1 && self.wrap_mode != WrapMode::None {
+            min(self.wrap_marker_width + self.indent, self.width / 2)
+        } else {
+            0
+        };
+        let width = self.width - self.row_indent;
+        // Escape sequences are interpreted, except when escape characters are shown.
+        let hyperlinks = !self.show_nonprinting;
+        let mut wrapped = String::new();
+        // A hyperlink or colors continued from the previous row are opened again, so that every row
+        // can be drawn by itself.
+        if let Some(open_hyperlink) = &self.open_hyperlink {
+            wrapped.push_str(open_hyperlink);
+        }
+        wrapped.push_str(&self.open_colors);
+        let prefix_len = wrapped.len();
+        let mut curr_width = 0;
+        // The length of `wrapped` up to and including its last whitespace, where a word wrap can
+        // break the line.
+        let mut word_end = None;
+        // The same for the last punctuation, where a word too long for the line can be broken.
+        let mut punct_end = None;
+        // Iterate over grapheme clusters rather than characters so that a cluster like an emoji
+        // sequence or a letter with accents is never split across lines.
+        for cluster in display_units(&line[self.line_pos..], hyperlinks) {
+            let is_whitespace = cluster.chars().all(char::is_whitespace);
+            let cluster_width = self.display_width(cluster, curr_width);
+            // A cluster wider than the whole line is put on a line by itself; otherwise we would
+            // never make progress.
+            if self.wrap_mode != WrapMode::None
+                && curr_width + cluster_width > width
+                && curr_width > 0
+            {
+                if self.max_rows == Some(self.rows) {
+                    // This is the last line allowed for the source line. Make room for the marker
+                    // and skip the rest.
+                    while curr_width + 1 > width {
+                        let last = match display_units(&wrapped, hyperlinks).last() {
+                            Some(last) => last,
+                            None => break,
+                        };
+                        wrapped.truncate(wrapped.len() - last.len());
+                        curr_width = self.row_width(&wrapped);
+                    }
+                    wrapped.push('…');
+                    self.close_sequences(&mut wrapped);
+                    self.finish_source_line();
+                    return Some(wrapped);
+                }
+
+                // If the overflowing cluster is a whitespace, the line already ends with a whole
+                // word. The whitespace is dropped so that the next row doesn't start with it.
+                let mut skipped = 0;
+                if self.wrap_mode == WrapMode::Word && !is_whitespace {
+                    if let Some(end) = word_end.or(punct_end) {
+                        wrapped.truncate(end);
+                    }
+                } else if self.wrap_mode == WrapMode::Word && !self.show_whitespace {
+                    skipped = cluster.len();
+                }
+                self.line_pos += wrapped.len() - prefix_len + skipped;
+                self.close_sequences(&mut wrapped);
+                return Some(wrapped);
+            }
+
+            curr_width += cluster_width;
+            wrapped.push_str(cluster);
+            // Breaking at leading whitespace would leave a blank line.
+            if is_whitespace && !wrapped[prefix_len..].trim_start().is_empty() {
+                word_end = Some(wrapped.len());
+            } else if BREAK_AFTER.contains(&cluster) {
+                punct_end = Some(wrapped.len());
+            }
+        }
+
+        // The `$` marking the end of the source line doesn't fit; it goes on a row by itself.
+        if self.show_whitespace
+            && self.wrap_mode != WrapMode::None
+            && curr_width + 1 > width
+            && self.max_rows != Some(self.rows)
+        {
+            self.line_pos = line.len();
+            self.close_sequences(&mut wrapped);
+            return Some(wrapped);
+        }
+
+        // The whole source line has been wrapped. Unless it was the last one, the next wrapped line
+        // belongs to the next source line.
+        self.close_sequences(&mut wrapped);
+        self.finish_source_line();
+
+        Some(wrapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(width: usize, contents: &str, setup: impl FnOnce(&mut LineBreaker)) -> Vec<String> {
+        let mut breaker = LineBreaker::new(width, contents);
+        setup(&mut breaker);
+        breaker.collect()
+    }
+
+    fn wrap_with_cr_mode(contents: &str, cr_mode: CrMode) -> Vec<String> {
+        wrap(80, contents, |breaker| breaker.set_cr_mode(cr_mode))
+    }
+
+    #[test]
+    fn strip_carriage_returns() {
+        assert_eq!(wrap_with_cr_mode("a\rb", CrMode::Strip), ["ab"]);
+        assert_eq!(wrap_with_cr_mode("a\r\nb", CrMode::Strip), ["a", "b"]);
+        assert_eq!(wrap_with_cr_mode("a\r", CrMode::Strip), ["a"]);
+    }
+
+    #[test]
+    fn break_at_carriage_returns() {
+        assert_eq!(wrap_with_cr_mode("a\rb", CrMode::Break), ["a", "b"]);
+        assert_eq!(wrap_with_cr_mode("a\r\nb", CrMode::Break), ["a", "b"]);
+        assert_eq!(wrap_with_cr_mode("a\r", CrMode::Break), ["a"]);
+    }
+
+    #[test]
+    fn overwrite_at_carriage_returns() {
+        assert_eq!(wrap_with_cr_mode("a\rb", CrMode::Overwrite), ["b"]);
+        assert_eq!(wrap_with_cr_mode("ab\rc", CrMode::Overwrite), ["cb"]);
+        assert_eq!(wrap_with_cr_mode("a\r\nb", CrMode::Overwrite), ["a", "b"]);
+        assert_eq!(wrap_with_cr_mode("a\r", CrMode::Overwrite), ["a"]);
+    }
+
+    #[test]
+    fn wrap_in_width_one() {
+        assert_eq!(wrap(1, "abc\nd", |_| {}), ["a", "b", "c", "d"]);
+        // A wide character doesn't fit, but it still makes progress on a row by itself.
+        assert_eq!(wrap(1, "あい", |_| {}), ["あ", "い"]);
+    }
+
+    #[test]
+    fn wrap_in_width_zero() {
+        assert_eq!(wrap(0, "ab\nc", |_| {}), ["a", "b", "c"]);
+        assert_eq!(
+            wrap(0, "ab cd", |breaker| breaker.set_wrap_mode(WrapMode::Word)),
+            ["a", "b", "c", "d"]
+        );
+    }
+
+    fn wrap_words(width: usize, contents: &str) -> Vec<String> {
+        wrap(width, contents, |breaker| {
+            breaker.set_wrap_mode(WrapMode::Word)
+        })
+    }
+
+    #[test]
+    fn wrap_at_whitespace() {
+        assert_eq!(
+            wrap_words(8, "hello world foo"),
+            ["hello ", "world ", "foo"]
+        );
+        // The whitespace which overflows is dropped rather than starting the next row.
+        assert_eq!(wrap_words(5, "hello world"), ["hello", "world"]);
+        assert_eq!(wrap_words(80, "hello world"), ["hello world"]);
+    }
+
+    #[test]
+    fn break_long_word() {
+        assert_eq!(wrap_words(4, "abcdefghij"), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap_words(6, "a abcdefghij"), ["a ", "abcdef", "ghij"]);
+        // A path is rather broken after a slash.
+        assert_eq!(wrap_words(8, "/usr/local/bin"), ["/usr/", "local/", "bin"]);
+    }
+
+    #[test]
+    fn cap_rows_of_huge_line() {
+        let contents = format!("{}\nnext", "x".repeat(1_000_000));
+        let rows = wrap(80, &contents, |breaker| breaker.set_max_rows(Some(3)));
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0], "x".repeat(80));
+        assert_eq!(rows[1], "x".repeat(80));
+        // The last row makes room for the marker, and the rest of the source line is skipped.
+        assert_eq!(rows[2], format!("{}…", "x".repeat(79)));
+        assert_eq!(rows[3], "next");
+    }
+
+    #[test]
+    fn wrap_hyperlink_by_text() {
+        let open = "\x1b]8;;https://example.com\x1b\\";
+        let line = format!("ab{}link text{} cd", open, HYPERLINK_END);
+        assert_eq!(str_width(&line), 14);
+        // The link is closed at the end of the first row and opened again on the next.
+        assert_eq!(
+            wrap(8, &line, |_| {}),
+            [
+                format!("ab{}link t{}", open, HYPERLINK_END),
+                format!("{}ext{} cd", open, HYPERLINK_END),
+            ]
+        );
+    }
+
+    #[test]
+    fn overstruck_characters() {
+        assert_eq!(overstrike("A\x08A"), Some(("A", true, false)));
+        assert_eq!(overstrike("_\x08A"), Some(("A", false, true)));
+        assert_eq!(overstrike("_\x08A\x08A"), Some(("A", true, true)));
+        assert_eq!(overstrike("A"), None);
+        // Each overstruck character takes a single column, also a wide one.
+        assert_eq!(cluster_width("A\x08A"), 1);
+        assert_eq!(cluster_width("あ\x08あ"), 2);
+        assert_eq!(str_width("N\x08NA\x08AM\x08ME\x08E _\x08x"), 6);
+        assert_eq!(
+            wrap(2, "N\x08NA\x08AM\x08ME\x08E", |_| {}),
+            ["N\x08NA\x08A", "M\x08ME\x08E"]
+        );
+    }
+
+    const FLAG: &str = "\u{1f1ef}\u{1f1f5}";
+    const FAMILY: &str = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+
+    #[test]
+    fn width_of_emoji_sequences() {
+        assert_eq!(cluster_width(FLAG), 2);
+        assert_eq!(cluster_width(FAMILY), 2);
+        // A heart is narrow unless the emoji presentation is selected.
+        assert_eq!(cluster_width("\u{2764}"), 1);
+        assert_eq!(cluster_width("\u{2764}\u{fe0f}"), 2);
+        assert_eq!(str_width(&format!("a{}{}b", FLAG, FAMILY)), 6);
+    }
+
+    #[test]
+    fn wrap_emoji_sequences() {
+        let flags = format!("a{}{}b", FLAG, FLAG);
+        assert_eq!(
+            wrap(5, &flags, |_| {}),
+            [format!("a{}{}", FLAG, FLAG), "b".to_string()]
+        );
+        assert_eq!(
+            wrap(4, &flags, |_| {}),
+            [format!("a{}", FLAG), format!("{}b", FLAG)]
+        );
+        let families = format!("{}{}", FAMILY, FAMILY);
+        assert_eq!(wrap(3, &families, |_| {}), [FAMILY, FAMILY]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::mem::take;
+use unicode_width::UnicodeWidthStr;
+
+/// A single line of text being typed in the prompt, with a cursor which can be moved around.
+#[derive(Clone, Default, Debug)]
+pub struct LineEdit {
+    text: String,
+    /// Byte offset of the cursor in `text`. Always on a character boundary.
+    cursor: usize,
+}
+
+impl LineEdit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Replaces the text, placing the cursor at the end.
+    pub fn set(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+    }
+
+    /// Takes out the text, leaving the line empty.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        take(&mut self.text)
+    }
+
+    /// Returns the display width of the text before the cursor, i.e. the column of the cursor.
+    pub fn cursor_width(&self) -> usize {
+        self.text[..self.cursor].width()
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    /// Deletes the character before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= ch.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Deletes the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Deletes the word before the cursor along with the whitespace after it, like Ctrl-W in a
+    /// shell.
+    pub fn delete_word(&mut self) {
+        let before = self.text[..self.cursor].trim_end();
+        let word_start = before
+            .char_indices()
+            .rev()
+            .find(|(_, ch)| ch.is_whitespace())
+            .map_or(0, |(idx, ch)| idx + ch.len_utf8());
+        self.text.replace_range(word_start..self.cursor, "");
+        self.cursor = word_start;
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(ch) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= ch.len_utf8();
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(ch) = self.text[self.cursor..].chars().next() {
+            self.cursor += ch.len_utf8();
+        }
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.text.len();
+    }
+}
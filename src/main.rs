@@ -1,398 +1,1749 @@
-use crossterm::cursor::{Hide, MoveTo, Show};
-use crossterm::event::{read, Event, KeyCode};
-use crossterm::queue;
-use crossterm::style::{Color, Print, PrintStyledContent, Stylize};
+use anyhow::{anyhow, bail, Context};
+use clap::{value_parser, Parser};
+use crossterm::cursor::MoveTo;
+use crossterm::event::{
+    poll, read, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers,
+    MouseButton, MouseEventKind,
+};
+use crossterm::execute;
+use crossterm::style::{Color, Print};
 use crossterm::terminal::{
-    disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen,
+    disable_raw_mode, enable_raw_mode, size, Clear, ClearType, EnterAlternateScreen,
+    LeaveAlternateScreen,
 };
 use crossterm::QueueableCommand;
+use pag::{
+    align_columns, decode, looks_binary, CaseMode, CrMode, LineEdit, LineNumbers, MoveUnit, Screen,
+    WrapMode,
+};
 use scopeguard::defer;
-use std::cell::{Cell, RefCell};
-use std::cmp::min;
-use std::env::args;
-use std::fs::read_to_string;
+use std::convert::TryFrom;
+use std::env::{args, var_os};
+use std::fs::{self, create_dir_all, read_to_string, write};
 use std::io::prelude::*;
-use std::io::{stdin, stdout};
-use std::mem::take;
+use std::io::{self, stdin, stdout};
+use std::mem::replace;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
 use unicode_width::UnicodeWidthChar;
 
-pub enum MoveUnit {
-    Line,
-    HalfPage,
-    Entire,
+/// Where the screen starts, given by a `+` option like `less`.
+enum StartPosition {
+    /// `+G`: the end of the input.
+    End,
+    /// `+N`: the (1-based) line `N`.
+    Line(usize),
+    /// `--offset=N`: the line containing the byte `N`.
+    Offset(usize),
+    /// `+/pattern`: the first match of `pattern`.
+    Search(String),
 }
 
-fn main() -> anyhow::Result<()> {
-    // Read entire input. You can pass the file path as an argument. If it was `-` or not specified,
-    // the input is read from stdin.
-    let input = {
-        let file_path = args().nth(1).filter(|n| n != "-");
-        match file_path {
-            Some(path) => read_to_string(path)?,
-            None => {
-                let mut buf = String::new();
-                stdin().read_to_string(&mut buf)?;
-                buf
+/// A command whose output is shown instead of a file or stdin.
+enum ChildCommand {
+    /// `--cmd COMMAND`: a command line run in the shell.
+    Shell(String),
+    /// `-- PROGRAM ARGS...`: a program run with the arguments as they are, without the shell.
+    Program(Vec<String>),
+}
+
+impl ChildCommand {
+    /// Returns the command line, e.g. for the messages.
+    fn name(&self) -> String {
+        match self {
+            ChildCommand::Shell(command) => command.clone(),
+            ChildCommand::Program(args) => args.join(" "),
+        }
+    }
+}
+
+/// Command line options.
+struct Options {
+    /// The files to show, one at a time. The standard input is shown if there are none.
+    file_paths: Vec<PathBuf>,
+    /// Draw on the alternate screen, so that the terminal is restored on exit. Otherwise the last
+    /// view is left on the terminal, like `less -X`.
+    alt_screen: bool,
+    command: Option<ChildCommand>,
+    /// Show only the stdout of `command`, not its stderr.
+    no_stderr: bool,
+    cr_mode: CrMode,
+    /// Where to wrap lines. The default depends on `columns`.
+    wrap_mode: Option<WrapMode>,
+    /// Exit without paging if the input fits in one screen, like `less -F`.
+    quit_if_one_screen: bool,
+    /// Exit when scrolling reaches the end of the input, like `less -E`.
+    quit_at_eof: bool,
+    /// Exit when scrolling down at the end of the input, i.e. reaching it the second time, like
+    /// `less -e`.
+    quit_at_second_eof: bool,
+    /// Lines of context kept above a search match.
+    scrolloff: usize,
+    /// Lines of context shown around the lines passing the filter.
+    filter_context: usize,
+    /// Continue searching from the other end when there are no more matches.
+    wrap_search: bool,
+    case_mode: CaseMode,
+    highlight_color: Color,
+    /// Print control characters as they are, like `less -r`.
+    raw_control_chars: bool,
+    /// Show a run of blank lines as one, like `less -s`.
+    squeeze_blank_lines: bool,
+    /// A file descriptor to read commands from while paging.
+    control_fd: Option<i32>,
+    /// Pretty-print the input if it is JSON.
+    json: bool,
+    /// Align the columns if the input is a table.
+    columns: bool,
+    /// The encoding of the file, instead of guessing it.
+    encoding: Option<String>,
+    /// Show the hex dump of the file even if it looks like text.
+    hex: bool,
+    /// Read the file again whenever it changes.
+    auto_reload: bool,
+    /// The number of lines pinned at the top of the screen.
+    sticky_header: usize,
+    /// The distance between tab stops.
+    tab_width: Option<usize>,
+    /// The maximum number of screen lines one source line takes.
+    max_rows: Option<usize>,
+    /// The character filling the gap left by a wide character wrapped to the next row.
+    wide_gap_marker: Option<char>,
+    /// The marker at the beginning of the continued rows of wrapped lines.
+    wrap_marker: Option<String>,
+    /// Indent the continued rows like the beginning of the line.
+    keep_indent: bool,
+    /// Show the scrollbar from the start.
+    scrollbar: bool,
+    line_numbers: LineNumbers,
+    /// The number of lines to scroll by a line, half a page and a page.
+    scroll_lines: Option<usize>,
+    half_page_lines: Option<usize>,
+    page_lines: Option<usize>,
+    /// The number of lines kept from the previous page when scrolling by a page.
+    page_overlap: usize,
+    start: Option<StartPosition>,
+}
+
+/// Simple pager which works on Windows.
+///
+/// Shows FILES one at a time, or the standard input if there are none. `+G` (or `--tail`) starts at
+/// the end of the input, `+N` at the line `N` and `+/PATTERN` at the first match of `PATTERN`.
+#[derive(Parser)]
+#[command(version, max_term_width = 100)]
+struct Args {
+    /// The files to show; `-` is the standard input
+    files: Vec<String>,
+    /// Run PROGRAM with ARGS, without the shell, and show its output
+    #[arg(last = true, value_name = "PROGRAM ARGS")]
+    program: Vec<String>,
+    /// Run COMMAND in the shell and show its output
+    #[arg(long = "cmd", value_name = "COMMAND")]
+    command: Option<String>,
+    /// Show only the stdout of the command, not its stderr
+    #[arg(long)]
+    no_stderr: bool,
+    /// Print the input and exit if it fits in one screen
+    #[arg(short = 'F', long)]
+    quit_if_one_screen: bool,
+    /// Exit when scrolling down reaches the end of the input
+    #[arg(short = 'E', long)]
+    quit_at_eof: bool,
+    /// Exit when scrolling down at the end of the input
+    #[arg(short = 'e', long)]
+    quit_at_second_eof: bool,
+    /// Don't use the alternate screen, leaving the last view on the terminal
+    #[arg(short = 'X', long)]
+    no_alt_screen: bool,
+    /// Show consecutive blank lines as a single blank line
+    #[arg(short, long)]
+    squeeze_blank_lines: bool,
+    /// Print control characters as they are
+    #[arg(short, long)]
+    raw_control_chars: bool,
+    /// Start at the end of the input, like `+G`
+    #[arg(long)]
+    tail: bool,
+    /// Start at the line containing the byte offset N
+    #[arg(long, value_name = "N")]
+    offset: Option<usize>,
+    /// Keep N lines of context above a search match
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    scrolloff: usize,
+    /// Show N lines around each line passing the filter
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    filter_context: usize,
+    /// Continue searching from the other end when there are no more matches
+    #[arg(long)]
+    wrap_search: bool,
+    /// Search ignoring the case unless the query has an uppercase letter
+    #[arg(short, long)]
+    ignore_case: bool,
+    /// Always search ignoring the case
+    #[arg(short = 'I', long = "IGNORE-CASE")]
+    ignore_case_always: bool,
+    /// The color of search matches, a name or `#rrggbb`
+    #[arg(long, value_name = "COLOR", value_parser = parse_color, default_value = "red")]
+    hl_color: Color,
+    /// The number of lines to scroll by a line
+    #[arg(long, value_name = "N", value_parser = parse_scroll_amount)]
+    scroll_lines: Option<usize>,
+    /// The number of lines to scroll by half a page
+    #[arg(long, value_name = "N", value_parser = parse_scroll_amount)]
+    half_page: Option<usize>,
+    /// The number of lines to scroll by a page
+    #[arg(long, value_name = "N", value_parser = parse_scroll_amount)]
+    page: Option<usize>,
+    /// The number of lines kept from the previous page when scrolling by a page, 1 or 2
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = value_parser!(u8).range(1..=2))]
+    page_overlap: u8,
+    /// Read commands from the file descriptor N while paging (Unix only)
+    #[arg(long, value_name = "N")]
+    control_fd: Option<i32>,
+    /// Show the scrollbar
+    #[arg(long)]
+    scrollbar: bool,
+    /// Show the line numbers
+    #[arg(short = 'N', long)]
+    line_numbers: bool,
+    /// Show the relative line numbers
+    #[arg(long)]
+    relative_line_numbers: bool,
+    /// Pretty-print the input if it is JSON
+    #[arg(long)]
+    json: bool,
+    /// Read the file in the encoding NAME instead of guessing it
+    #[arg(long, value_name = "NAME")]
+    encoding: Option<String>,
+    /// Show the hex dump of the file
+    #[arg(long)]
+    hex: bool,
+    /// Read the file again whenever it changes
+    #[arg(long)]
+    auto_reload: bool,
+    /// Align the columns of tab- or comma-separated input
+    #[arg(long)]
+    columns: bool,
+    /// Keep the first N lines at the top of the screen
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    header: usize,
+    /// Show at most N screen lines of each line
+    #[arg(long, value_name = "N")]
+    max_rows: Option<usize>,
+    /// Put the tab stops every N columns
+    #[arg(long, value_name = "N", value_parser = parse_tab_width)]
+    tabs: Option<usize>,
+    /// Fill the gap left by a wide character wrapped to the next line with CHAR
+    #[arg(long, value_name = "CHAR", value_parser = parse_wide_gap_marker)]
+    wide_gap: Option<char>,
+    /// Show STR at the beginning of the rows continuing a wrapped line
+    #[arg(long, value_name = "STR")]
+    wrap_marker: Option<String>,
+    /// Indent the rows continuing a wrapped line like the beginning of the line
+    #[arg(long)]
+    wrap_indent: bool,
+    /// How carriage returns are treated: strip, break or overwrite
+    #[arg(long = "cr", value_name = "MODE", default_value = "strip")]
+    cr_mode: CrMode,
+    /// Don't wrap long lines, like `--wrap=none`
+    #[arg(short = 'S', long)]
+    chop_long_lines: bool,
+    /// Where long lines are wrapped: char, word or none
+    #[arg(long = "wrap", value_name = "MODE")]
+    wrap_mode: Option<WrapMode>,
+}
+
+impl Options {
+    fn parse() -> anyhow::Result<Self> {
+        let args = match Args::try_parse_from(args()) {
+            Ok(args) => args,
+            Err(e) => {
+                // `--help` and `--version` are not errors.
+                let _ = e.print();
+                let code = if e.use_stderr() { EXIT_FAILURE } else { 0 };
+                std::process::exit(code.into());
+            }
+        };
+        // The `+` options, which clap doesn't know, come among the files. Other arguments starting
+        // with `+`, e.g. `+notes`, are files.
+        let mut start = None;
+        let mut file_paths = vec![];
+        for arg in args.files {
+            match parse_start_position(&arg) {
+                Some(position) => start = Some(position),
+                None if arg != "-" => file_paths.push(PathBuf::from(arg)),
+                None => {}
+            }
+        }
+        let start = start.or(match (args.offset, args.tail) {
+            (Some(offset), _) => Some(StartPosition::Offset(offset)),
+            (None, true) => Some(StartPosition::End),
+            (None, false) => None,
+        });
+        let command = match (args.command, args.program) {
+            (Some(command), _) => Some(ChildCommand::Shell(command)),
+            (None, program) if !program.is_empty() => Some(ChildCommand::Program(program)),
+            (None, _) => None,
+        };
+        // The screen showing the output of a command has no file to reload or open in the editor.
+        if command.is_some() && !file_paths.is_empty() {
+            bail!("files can't be shown together with the output of --cmd or --");
+        }
+        let case_mode = if args.ignore_case_always {
+            CaseMode::Insensitive
+        } else if args.ignore_case {
+            CaseMode::Smart
+        } else {
+            CaseMode::Sensitive
+        };
+        let line_numbers = if args.relative_line_numbers {
+            LineNumbers::Relative
+        } else if args.line_numbers {
+            LineNumbers::Absolute
+        } else {
+            LineNumbers::Hidden
+        };
+
+        Ok(Self {
+            file_paths,
+            alt_screen: !args.no_alt_screen,
+            command,
+            no_stderr: args.no_stderr,
+            cr_mode: args.cr_mode,
+            wrap_mode: if args.chop_long_lines {
+                Some(WrapMode::None)
+            } else {
+                args.wrap_mode
+            },
+            quit_if_one_screen: args.quit_if_one_screen,
+            quit_at_eof: args.quit_at_eof,
+            quit_at_second_eof: args.quit_at_second_eof,
+            scrolloff: args.scrolloff,
+            filter_context: args.filter_context,
+            wrap_search: args.wrap_search,
+            case_mode,
+            highlight_color: args.hl_color,
+            squeeze_blank_lines: args.squeeze_blank_lines,
+            raw_control_chars: args.raw_control_chars,
+            control_fd: args.control_fd,
+            json: args.json,
+            columns: args.columns,
+            hex: args.hex,
+            auto_reload: args.auto_reload,
+            encoding: args.encoding,
+            sticky_header: args.header,
+            max_rows: args.max_rows,
+            tab_width: args.tabs,
+            wide_gap_marker: args.wide_gap,
+            wrap_marker: args.wrap_marker.filter(|marker| !marker.is_empty()),
+            keep_indent: args.wrap_indent,
+            scrollbar: args.scrollbar,
+            line_numbers,
+            scroll_lines: args.scroll_lines,
+            half_page_lines: args.half_page,
+            page_lines: args.page,
+            page_overlap: args.page_overlap.into(),
+            start,
+        })
+    }
+}
+
+/// Exit status when something went wrong, e.g. the file couldn't be read.
+const EXIT_FAILURE: u8 = 1;
+/// Exit status when there was nothing to show.
+const EXIT_EMPTY_INPUT: u8 = 2;
+
+/// Parses a start position given like `less`: `+G`, `+N` or `+/PATTERN`.
+fn parse_start_position(arg: &str) -> Option<StartPosition> {
+    let position = arg.strip_prefix('+')?;
+    if position == "G" {
+        Some(StartPosition::End)
+    } else if let Some(pattern) = position.strip_prefix('/') {
+        Some(StartPosition::Search(pattern.to_string()))
+    } else {
+        position.parse().ok().map(StartPosition::Line)
+    }
+}
+
+/// Parses the number of lines to scroll by, which must be positive.
+fn parse_scroll_amount(s: &str) -> anyhow::Result<usize> {
+    match s.parse() {
+        Ok(lines) if lines > 0 => Ok(lines),
+        _ => bail!(
+            "invalid number of lines `{}` (expected a positive number)",
+            s
+        ),
+    }
+}
+
+/// Parses the distance between tab stops, which must be positive.
+fn parse_tab_width(s: &str) -> anyhow::Result<usize> {
+    match s.parse() {
+        Ok(width) if width > 0 => Ok(width),
+        _ => bail!("invalid tab width `{}` (expected a positive number)", s),
+    }
+}
+
+/// Parses the marker of the gap left by a wide character, which must be a narrow character.
+fn parse_wide_gap_marker(s: &str) -> anyhow::Result<char> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) if ch.width() == Some(1) => Ok(ch),
+        _ => bail!("invalid marker `{}` (expected a narrow character)", s),
+    }
+}
+
+/// Parses a color name such as `yellow` or `dark_blue`, or an RGB value like `#ff8800`.
+fn parse_color(s: &str) -> anyhow::Result<Color> {
+    if let Some(hex) = s.strip_prefix('#') {
+        if hex.len() == 6 && hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            let component = |idx: usize| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap();
+            return Ok(Color::Rgb {
+                r: component(0),
+                g: component(2),
+                b: component(4),
+            });
+        }
+    }
+
+    Color::try_from(s).map_err(|_| {
+        anyhow!(
+            "unknown color `{}` (expected a name like `red` or `#RRGGBB`)",
+            s
+        )
+    })
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("(error: {:#})", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn run() -> anyhow::Result<ExitCode> {
+    let options = Options::parse()?;
+
+    // Read input. You can pass the file path as an argument. If it was `-` or not specified, the
+    // input is read from stdin. Since stdin may be slow or endless, it is read in the background
+    // and the screen is shown as soon as the first chunk arrives. So is the output of `--cmd`, and
+    // of the program decompressing a compressed file, like `zless`.
+    // The files after the first are read when they are shown.
+    // The exit status of the command, once it has exited, is the exit status of pag.
+    let mut command_exit_code = Arc::new(OnceLock::new());
+    let file_path = options.file_paths.first().cloned();
+    let decompressor = match (&options.command, &file_path) {
+        (None, Some(path)) => decompressor_for(path),
+        _ => None,
+    };
+    let mut is_binary_file = false;
+    let mut encoding = None;
+    let (mut input, mut loader) = match (&options.command, &file_path) {
+        (None, Some(path)) if decompressor.is_none() => {
+            let (input, file_encoding, is_binary) = read_file(path, &options)?;
+            encoding = file_encoding;
+            is_binary_file = is_binary;
+            (input, None)
+        }
+        (command, _) => {
+            let (loader, source) = match (command, decompressor, &file_path) {
+                (Some(command), ..) => (
+                    spawn_command_reader(command, options.no_stderr, command_exit_code.clone())?,
+                    format!("the output of `{}`", command.name()),
+                ),
+                (None, Some(program), Some(path)) => (
+                    spawn_decompressor_reader(program, path)?,
+                    format!("`{}`", path.display()),
+                ),
+                _ => (spawn_stdin_reader(), "the standard input".to_string()),
+            };
+            match loader.recv() {
+                Ok(chunk) => {
+                    let chunk = chunk.with_context(|| format!("failed to read {}", source))?;
+                    // Binary input is told from its beginning, like a file.
+                    is_binary_file = looks_binary(chunk.as_bytes());
+                    (chunk, Some(loader))
+                }
+                Err(_) => (String::new(), None),
             }
         }
     };
 
     if input.is_empty() {
-        println!("(error: input was empty)");
-        return Ok(());
+        eprintln!("(error: input was empty)");
+        return Ok(ExitCode::from(EXIT_EMPTY_INPUT));
+    }
+
+    // Formatting needs the whole input, so wait for the rest of it.
+    if options.json || options.columns {
+        if let Some(rx) = loader.take() {
+            for chunk in rx {
+                input.push_str(&chunk?);
+            }
+        }
     }
 
+    let format_error = format_input(&mut input, options.json, options.columns);
+
     let (width, height) = match term_size::dimensions_stdout() {
         Some((w, h)) => (w, h),
         None => {
             eprintln!("(error: Failed to get dimension)");
-            println!("{}", input);
-            return Ok(());
+            print!("{}", input);
+            for chunk in loader.iter().flatten() {
+                print!("{}", chunk?);
+            }
+            return Ok(exit_code(&command_exit_code));
         }
     };
 
     let mut scr = Screen::new(width, height, input);
+    configure_screen(&mut scr, &options);
+    // The contents of a compressed file are not the file itself, which can't be reloaded,
+    // followed or edited.
+    if let Some(path) = file_path.filter(|_| decompressor.is_none()) {
+        scr.set_path(path);
+    }
+    scr.set_encoding(encoding);
+    let mut files = Files {
+        screens: options.file_paths.iter().map(|_| None).collect(),
+        current: 0,
+    };
+
+    // With more files to show, the first one is paged even if it fits.
+    if options.quit_if_one_screen && options.file_paths.len() <= 1 {
+        // Wait until the input turns out to be longer than one screen, or print it like `cat`.
+        while scr.fits_in_screen() {
+            let rx = match &loader {
+                Some(rx) => rx,
+                None => {
+                    print!("{}", scr.get_contents());
+                    return Ok(exit_code(&command_exit_code));
+                }
+            };
+            match rx.recv() {
+                Ok(chunk) => scr.append(&chunk?),
+                Err(_) => loader = None,
+            }
+        }
+    }
+
+    scr.set_loading(loader.is_some());
+    show_input_notes(&mut scr, &options, format_error, is_binary_file, encoding);
+
+    // Restore the query from the previous run so that `n` works right away.
+    scr.get_query_mut().set(load_last_search());
+    scr.set_search_history(load_search_history());
+
+    match &options.start {
+        Some(StartPosition::End) => scr.down_by(MoveUnit::Entire, 1),
+        Some(StartPosition::Line(line_number)) => scr.goto_line(*line_number),
+        Some(StartPosition::Offset(offset)) => scr.goto_offset(*offset),
+        Some(StartPosition::Search(pattern)) => {
+            scr.get_query_mut().set(pattern.clone());
+            scr.first();
+        }
+        None => {}
+    }
 
     // enable raw mode
+    let alt_screen = options.alt_screen;
+    install_panic_hook(alt_screen);
     enable_raw_mode().unwrap();
     defer! {
+        execute!(stdout(), DisableMouseCapture).unwrap();
         disable_raw_mode().unwrap();
     }
 
     // enable alternate screen
-    stdout().queue(EnterAlternateScreen).unwrap();
+    if alt_screen {
+        stdout().queue(EnterAlternateScreen).unwrap();
+    } else {
+        // Scroll what the terminal shows up into its scrollback rather than drawing over it.
+        print!("{}", "\n".repeat(height));
+    }
     defer! {
-        stdout().queue(LeaveAlternateScreen).unwrap();
+        if alt_screen {
+            stdout().queue(LeaveAlternateScreen).unwrap();
+        } else {
+            // Leave the last view as it is and clear the prompt for the shell.
+            let (_, rows) = size().unwrap();
+            execute!(stdout(), MoveTo(0, rows.saturating_sub(1)), Clear(ClearType::CurrentLine))
+                .unwrap();
+        }
     }
 
+    let mut control = match options.control_fd {
+        Some(fd) => Some(spawn_control_reader(fd)?),
+        None => None,
+    };
+    let mut clipboard = Clipboard::default();
     let mut orig_query = None;
+    let mut pending_count: Option<usize> = None;
+    // The first key of a two-key command typed so far: `z` of `zz`, `zt` or `zb`, `m` followed by
+    // the mark to set, or `'` followed by the mark to jump to.
+    let mut pending_prefix = None;
+    // The file selected in the list of the files shown with `=`, while it is shown.
+    let mut file_list = None;
+    // The mouse is only captured for the scrollbar, so that text can be selected otherwise.
+    let mut mouse_captured = false;
+    let mut dragging_scrollbar = false;
     loop {
         use self::Event::*;
         use self::KeyCode::*;
 
-        scr.draw();
+        if scr.is_scrollbar_shown() != mouse_captured {
+            mouse_captured = !mouse_captured;
+            if mouse_captured {
+                execute!(stdout(), EnableMouseCapture)?;
+            } else {
+                execute!(stdout(), DisableMouseCapture)?;
+            }
+        }
+
+        if let Some(rx) = &loader {
+            // The input being loaded is of the first file, which may not be shown now.
+            let first = files.first_screen(&mut scr);
+            if !receive_input(first, rx) {
+                loader = None;
+                first.set_loading(false);
+            }
+        }
+        if let Some(rx) = &control {
+            if !receive_control(&mut scr, rx) {
+                control = None;
+            }
+        }
+
+        // Draw only when no more events are pending. While a key is held, the events queue up
+        // faster than the screen can be drawn; handling all of them before drawing once keeps
+        // scrolling smooth. Every event is still handled, so no keystroke is lost.
+        if !poll(Duration::ZERO)? {
+            scr.draw(&mut stdout().lock())?;
+        }
+
+        // While loading, wake up regularly to show the new input and animate the spinner. The
+        // control commands and a file being followed or reloaded are checked likewise. Otherwise
+        // `read` below blocks without using the CPU until the next event; resizing the terminal is
+        // an event too, so the screen reflows right away without waiting for a key.
+        let follows_file = scr.is_following() && loader.is_none();
+        let reloads_file = options.auto_reload && scr.get_path().is_some() && !follows_file;
+        if (loader.is_some() || control.is_some() || follows_file || reloads_file)
+            && !poll(Duration::from_millis(100))?
+        {
+            if loader.is_some() {
+                scr.tick_loading();
+            }
+            if follows_file {
+                scr.read_file_growth();
+            }
+            if reloads_file {
+                scr.reload_if_changed();
+            }
+            continue;
+        }
+
         if scr.is_query_mode() {
             // search query mode
             match read()? {
                 Resize(_, _) => scr.resized(),
                 Key(key) => match key.code {
                     Enter => {
+                        scr.push_search_history();
                         scr.set_query_mode(false);
                     }
+                    Up => {
+                        scr.prev_search_history();
+                        scr.search_incrementally();
+                    }
+                    Down => {
+                        scr.next_search_history();
+                        scr.search_incrementally();
+                    }
                     Esc => {
                         // restore original query. it must be saved hence unwrapping.
-                        *scr.get_query_mut() = orig_query.unwrap();
-                        orig_query = None;
+                        scr.get_query_mut().set(orig_query.take().unwrap());
+                        scr.restore_query_origin();
                         scr.set_query_mode(false);
                     }
-                    Backspace => {
-                        let _ = scr.get_query_mut().pop();
+                    _ => {
+                        edit_line(scr.get_query_mut(), key);
+                        scr.search_incrementally();
                     }
-                    Char(ch) => {
-                        scr.get_query_mut().push(ch);
+                },
+                _ => {}
+            }
+        } else if let Some(selected) = file_list {
+            let num_files = files.screens.len();
+            let selected = match read()? {
+                Resize(_, _) => {
+                    scr.resized();
+                    Some(selected)
+                }
+                Key(key) => match key.code {
+                    Down | Char('j') => Some((selected + 1) % num_files),
+                    Up | Char('k') => Some((selected + num_files - 1) % num_files),
+                    Enter => {
+                        if selected != files.current {
+                            switch_file(&mut scr, &mut files, selected, &options);
+                        }
+                        None
                     }
-                    _ => {}
+                    _ => None,
+                },
+                _ => Some(selected),
+            };
+            file_list = selected;
+            scr.set_overlay(
+                selected.map(|selected| (file_list_items(&scr, &files, &options), selected)),
+            );
+        } else if scr.is_label_mode() {
+            match read()? {
+                Resize(_, _) => scr.resized(),
+                Key(key) => match key.code {
+                    Char(label) => scr.jump_to_label(label),
+                    _ => scr.set_label_mode(false),
+                },
+                _ => {}
+            }
+        } else if scr.is_command_mode() {
+            match read()? {
+                Resize(_, _) => scr.resized(),
+                Key(key) => match key.code {
+                    Enter => {
+                        scr.set_command_mode(false);
+                        let command = scr.get_command_mut().take();
+                        run_command(&mut scr, &mut files, &options, &command);
+                    }
+                    Esc => {
+                        scr.get_command_mut().clear();
+                        scr.set_command_mode(false);
+                    }
+                    _ => edit_line(scr.get_command_mut(), key),
+                },
+                _ => {}
+            }
+        } else if scr.is_filter_mode() {
+            match read()? {
+                Resize(_, _) => scr.resized(),
+                Key(key) => match key.code {
+                    Enter => {
+                        scr.set_filter_mode(false);
+                        let pattern = scr.get_filter_input_mut().take();
+                        scr.set_filter(&pattern);
+                    }
+                    Esc => {
+                        scr.get_filter_input_mut().clear();
+                        scr.set_filter_mode(false);
+                    }
+                    _ => edit_line(scr.get_filter_input_mut(), key),
                 },
                 _ => {}
             }
         } else {
             // Normal mode
+            let was_at_end = scr.is_at_end();
+            let mut scrolled_down = false;
+            let mut down_by = |scr: &mut Screen, unit, count| {
+                scr.down_by(unit, count);
+                scrolled_down = true;
+            };
             match read()? {
                 Resize(_, _) => scr.resized(),
-                Key(key) => match key.code {
-                    Enter | Down | Char('j') => scr.down_by(MoveUnit::Line),
-                    Up | Char('k') => scr.up_by(MoveUnit::Line),
-                    Char(' ' | 'f' | 'd') => scr.down_by(MoveUnit::HalfPage),
-                    Char('b' | 'u') => scr.up_by(MoveUnit::HalfPage),
-                    Char('g') => scr.up_by(MoveUnit::Entire),
-                    Char('G') => scr.down_by(MoveUnit::Entire),
-                    Char('q') => break,
-                    Char('/') => {
-                        orig_query = Some(take(scr.get_query_mut()));
-                        scr.set_query_mode(true);
+                Key(key) => {
+                    if let Some(prefix) = pending_prefix.take() {
+                        match (prefix, key.code) {
+                            ('z', Char('z')) => scr.center(),
+                            ('z', Char('t')) => scr.current_line_to_top(),
+                            ('z', Char('b')) => scr.current_line_to_bottom(),
+                            ('m', Char(mark)) => scr.set_mark(mark),
+                            ('\'', Char(mark)) => scr.jump_to_mark(mark),
+                            _ => {}
+                        }
+                        continue;
+                    }
+
+                    // Digits before a command are its count, e.g. `5j` scrolls down 5 lines. A
+                    // leading `0` doesn't start a count.
+                    if let Char(digit @ '0'..='9') = key.code {
+                        if digit != '0' || pending_count.is_some() {
+                            let digit = digit.to_digit(10).unwrap() as usize;
+                            let count = pending_count
+                                .unwrap_or(0)
+                                .saturating_mul(10)
+                                .saturating_add(digit);
+                            pending_count = Some(count);
+                            scr.show_message(count.to_string());
+                            continue;
+                        }
                     }
-                    Char('n') => scr.next(),
-                    Char('N') => scr.prev(),
+
+                    // Any command stops following the input, except `F` itself.
+                    if scr.is_following() && key.code != Char('F') {
+                        scr.set_following(false);
+                    }
+
+                    // `g` and `G` take the count as a line number instead, and `%` as a percentage.
+                    let line_number = pending_count;
+                    let count = pending_count.take().unwrap_or(1);
+                    match key.code {
+                        Enter | Down | Char('j') => down_by(&mut scr, MoveUnit::Line, count),
+                        Up | Char('k') => scr.up_by(MoveUnit::Line, count),
+                        PageDown | Char(' ' | 'f') => down_by(&mut scr, MoveUnit::Page, count),
+                        PageUp | Char('b') => scr.up_by(MoveUnit::Page, count),
+                        Char('d') => down_by(&mut scr, MoveUnit::HalfPage, count),
+                        Char('u') => scr.up_by(MoveUnit::HalfPage, count),
+                        Char('}') => {
+                            scr.next_paragraph(count);
+                            scrolled_down = true;
+                        }
+                        Char('{') => scr.prev_paragraph(count),
+                        Char('g' | 'G') if line_number.is_some() => {
+                            scr.goto_line(count);
+                        }
+                        Char('g') => scr.up_by(MoveUnit::Entire, 1),
+                        Char('G') => down_by(&mut scr, MoveUnit::Entire, 1),
+                        Char('q') => break,
+                        Char('/') => {
+                            orig_query = Some(scr.get_query_mut().take());
+                            scr.set_query_mode(true);
+                        }
+                        Char(':') => scr.set_command_mode(true),
+                        Char('|') => {
+                            // The shell command is typed after `:|`.
+                            scr.get_command_mut().set("|".to_string());
+                            scr.set_command_mode(true);
+                        }
+                        Char('s') => {
+                            // The file name is typed after `:w`.
+                            scr.get_command_mut().set("w ".to_string());
+                            scr.set_command_mode(true);
+                        }
+                        Char('&') => scr.set_filter_mode(true),
+                        Char('n') => (0..count).for_each(|_| scr.next()),
+                        Char('N') => (0..count).for_each(|_| scr.prev()),
+                        Char('*') => scr.count_visible_matches(),
+                        Char('I') => scr.toggle_case_mode(),
+                        Left | Char('h') => scr.scroll_left(count),
+                        Right | Char('l') => scr.scroll_right(count),
+                        Char('S') => scr.toggle_status_line(),
+                        Char('W') => scr.toggle_nonprinting(),
+                        Char('A') => scr.toggle_whitespace(),
+                        Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            scr.switch_pane();
+                        }
+                        Char('w') => scr.toggle_wrap(),
+                        Char('_') => scr.toggle_split(),
+                        Char('L') => scr.set_label_mode(true),
+                        Char(prefix @ ('z' | 'm' | '\'')) => {
+                            pending_prefix = Some(prefix);
+                            scr.show_message(prefix.to_string());
+                        }
+                        Char('=') if files.screens.len() > 1 => {
+                            file_list = Some(files.current);
+                            let items = file_list_items(&scr, &files, &options);
+                            scr.set_overlay(Some((items, files.current)));
+                            scr.show_message("choose a file with j, k and <Enter>");
+                        }
+                        Char('=') => scr.show_message("no other files"),
+                        Char('R') => match (&options.command, files.current) {
+                            // The output of the command is read again by running it again.
+                            (Some(command), 0) => {
+                                command_exit_code = Arc::new(OnceLock::new());
+                                let exit_code = command_exit_code.clone();
+                                match spawn_command_reader(command, options.no_stderr, exit_code) {
+                                    Ok(rx) => {
+                                        loader = Some(rx);
+                                        scr.restart_contents();
+                                        scr.set_loading(true);
+                                    }
+                                    Err(e) => scr.show_message(format!("{:#}", e)),
+                                }
+                            }
+                            _ => scr.reload(),
+                        },
+                        Char('H') => scr.toggle_hex_dump(),
+                        Char('[') => scr.set_selection_start(),
+                        Char(']') => scr.set_selection_end(),
+                        Esc => scr.clear_selection(),
+                        Char('y') => {
+                            // The selection if any, or the lines on the screen.
+                            let text = scr.text_to_copy();
+                            let num_lines = text.lines().count();
+                            match clipboard.copy(text) {
+                                Ok(()) => scr.show_message(format!("copied {} lines", num_lines)),
+                                Err(e) => scr.show_message(format!("failed to copy: {}", e)),
+                            }
+                        }
+                        Char('v') => match scr.get_path() {
+                            Some(path) => {
+                                let line_number = scr.top_line_number();
+                                if let Err(e) = open_in_editor(path, line_number, alt_screen) {
+                                    scr.show_message(format!("failed to open the editor: {}", e));
+                                }
+                                // Show the changes made in the editor, like `less`.
+                                scr.reload_if_changed();
+                                scr.redraw();
+                            }
+                            None => scr.show_message("cannot edit the standard input"),
+                        },
+                        Char('B') => scr.toggle_scrollbar(),
+                        Char('#') => scr.toggle_line_numbers(),
+                        Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                            scr.jump_back();
+                        }
+                        // The terminal sends `Ctrl-I` as a tab.
+                        Tab => scr.jump_forward(),
+                        Char('%') if line_number.is_some() => scr.goto_percent(count),
+                        Char('%') => scr.goto_matching_bracket(),
+                        Char('F') => scr.set_following(true),
+                        _ => {}
+                    }
+                }
+                Mouse(mouse) => match mouse.kind {
+                    MouseEventKind::Down(MouseButton::Left)
+                        if scr.is_scrollbar_column(mouse.column as usize) =>
+                    {
+                        dragging_scrollbar = true;
+                        scr.scroll_to_scrollbar_row(mouse.row as usize);
+                    }
+                    MouseEventKind::Drag(MouseButton::Left) if dragging_scrollbar => {
+                        scr.scroll_to_scrollbar_row(mouse.row as usize);
+                    }
+                    MouseEventKind::Up(_) => dragging_scrollbar = false,
+                    MouseEventKind::ScrollDown => down_by(&mut scr, MoveUnit::Line, 3),
+                    MouseEventKind::ScrollUp => scr.up_by(MoveUnit::Line, 3),
                     _ => {}
                 },
-                _ => {}
+            }
+
+            let quits_at_end = options.quit_at_eof || options.quit_at_second_eof && was_at_end;
+            if quits_at_end && scrolled_down && loader.is_none() && scr.is_at_end() {
+                break;
             }
         }
     }
 
-    Ok(())
+    save_last_search(scr.get_query());
+    save_search_history(scr.search_history());
+
+    Ok(exit_code(&command_exit_code))
 }
 
-pub struct Screen {
-    width: usize,
-    height: usize,
-    contents: String,
-    lines: Vec<String>,
-    current_top: isize,
-    query_mode: bool,
-    query: String,
-    message: RefCell<Option<String>>,
-    needs_update: Cell<bool>,
-}
-
-impl Screen {
-    pub fn new(width: usize, height: usize, contents: String) -> Self {
-        let mut scr = Self {
-            width,
-            height,
-            contents,
-            lines: vec![],
-            current_top: 0,
-            query_mode: false,
-            query: String::new(),
-            message: RefCell::new(None),
-            needs_update: Cell::new(true),
-        };
-        scr.recalc_lines();
+/// Returns the exit status of pag: the one of the command shown if it has exited, or success.
+fn exit_code(command_exit_code: &OnceLock<i32>) -> ExitCode {
+    match command_exit_code.get() {
+        Some(&code) => ExitCode::from(u8::try_from(code).unwrap_or(EXIT_FAILURE)),
+        None => ExitCode::SUCCESS,
+    }
+}
+
+/// Applies a key typed at the prompt to `line`.
+fn edit_line(line: &mut LineEdit, key: KeyEvent) {
+    use self::KeyCode::*;
+
+    match key.code {
+        Backspace => line.backspace(),
+        Delete => line.delete(),
+        Left => line.move_left(),
+        Right => line.move_right(),
+        Home => line.move_home(),
+        End => line.move_end(),
+        Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => line.clear(),
+        Char('w') if key.modifiers.contains(KeyModifiers::CONTROL) => line.delete_word(),
+        Char(ch) => line.insert(ch),
+        _ => {}
+    }
+}
 
-        scr
+/// Runs a command typed at the `:` prompt. A number jumps to that line, like `less`, and `n` and
+/// `p` show the next and the previous file.
+fn run_command(scr: &mut Screen, files: &mut Files, options: &Options, command: &str) {
+    let command = command.trim();
+    if command.is_empty() {
+        return;
     }
 
-    pub fn resized(&mut self) {
-        let (width, height) = term_size::dimensions_stdout().unwrap();
-        self.update_size(width as usize, height as usize)
+    match command {
+        "n" if files.current + 1 < files.screens.len() => {
+            return switch_file(scr, files, files.current + 1, options);
+        }
+        "n" => return scr.show_message("no next file"),
+        "p" if files.current > 0 => return switch_file(scr, files, files.current - 1, options),
+        "p" => return scr.show_message("no previous file"),
+        "w" | "w!" => return scr.show_message("`:w` needs a file name"),
+        _ => {}
+    }
+
+    if let Some(shell_command) = command.strip_prefix('|') {
+        return pipe_contents(scr, shell_command.trim(), options.alt_screen);
+    }
+
+    // `:w FILE` saves the contents, and `:w! FILE` overwrites an existing file too.
+    match command.split_once(char::is_whitespace) {
+        Some(("w", path)) => return save_contents(scr, Path::new(path.trim()), false),
+        Some(("w!", path)) => return save_contents(scr, Path::new(path.trim()), true),
+        _ => {}
     }
 
-    pub fn update_size(&mut self, width: usize, height: usize) {
-        if self.width == width && self.height == height {
-            return;
+    if let Some(percent) = command.strip_suffix('%') {
+        match percent.parse() {
+            Ok(percent) => scr.goto_percent(percent),
+            Err(_) => scr.show_message(format!("invalid percentage `{}`", percent)),
         }
+        return;
+    }
 
-        self.width = width;
-        self.height = height;
-        self.recalc_lines();
-        self.fix_current_top();
+    match command.parse() {
+        Ok(line_number) => scr.goto_line(line_number),
+        Err(_) => scr.show_message(format!("unknown command `{}`", command)),
     }
+}
 
-    pub fn get_query(&self) -> &str {
-        &self.query
+/// Writes the selected lines of `scr` to the file at `path`, or the whole contents (the lines shown
+/// while filtering) if nothing is selected. An existing file is overwritten only if `overwrite`.
+fn save_contents(scr: &mut Screen, path: &Path, overwrite: bool) {
+    if !overwrite && path.exists() {
+        return scr.show_message(format!(
+            "`{}` exists; overwrite it with `:w!`",
+            path.display()
+        ));
     }
 
-    pub fn get_query_mut(&mut self) -> &mut String {
-        self.needs_update.set(true);
-        &mut self.query
+    let result = match scr.selected_lines().or_else(|| scr.filtered_lines()) {
+        Some(lines) => write(path, join_lines(&lines)),
+        None => write(path, scr.get_contents()),
+    };
+    match result {
+        Ok(()) => scr.show_message(format!("saved to `{}`", path.display())),
+        Err(e) => scr.show_message(format!("failed to save to `{}`: {}", path.display(), e)),
     }
+}
 
-    pub fn is_query_mode(&self) -> bool {
-        self.query_mode
+/// Runs `command` in the shell with the selected lines as its input, or the whole contents (the
+/// lines shown while filtering) if nothing is selected, like `|` of `less`. Its output is shown on
+/// the terminal instead of the screen until a key is typed.
+fn pipe_contents(scr: &mut Screen, command: &str, alt_screen: bool) {
+    if command.is_empty() {
+        return scr.show_message("`:|` needs a command");
     }
 
-    pub fn set_query_mode(&mut self, mode: bool) {
-        self.needs_update.set(true);
-        self.query_mode = mode;
+    let text = match scr.selected_lines().or_else(|| scr.filtered_lines()) {
+        Some(lines) => join_lines(&lines),
+        None => scr.get_contents().to_string(),
+    };
+    if let Err(e) = run_with_input(command, text, alt_screen) {
+        scr.show_message(format!("failed to run `{}`: {:#}", command, e));
     }
+    scr.redraw();
+}
 
-    pub fn up_by(&mut self, unit: MoveUnit) {
-        match unit {
-            MoveUnit::Line => self.scroll(-1),
-            MoveUnit::HalfPage => self.scroll(-(self.height as isize) / 2),
-            MoveUnit::Entire => self.scroll(-isize::MAX),
+/// Runs `command` in the shell with `text` as its stdin on the terminal left by the pager, and
+/// waits for a key after it exits.
+fn run_with_input(command: &str, text: String, alt_screen: bool) -> anyhow::Result<()> {
+    disable_raw_mode()?;
+    if alt_screen {
+        execute!(stdout(), LeaveAlternateScreen)?;
+    }
+    defer! {
+        if alt_screen {
+            execute!(stdout(), EnterAlternateScreen).unwrap();
         }
+        enable_raw_mode().unwrap();
     }
 
-    pub fn down_by(&mut self, unit: MoveUnit) {
-        match unit {
-            MoveUnit::Line => self.scroll(1),
-            MoveUnit::HalfPage => self.scroll((self.height as isize) / 2),
-            MoveUnit::Entire => self.scroll(isize::MAX),
-        }
+    let mut child = shell_command(command).stdin(Stdio::piped()).spawn()?;
+    // The input is written in another thread, so that a command which doesn't read all of it,
+    // e.g. `head`, isn't blocked by the pipe.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        let _ = stdin.write_all(text.as_bytes());
+    });
+    let status = child.wait()?;
+    let _ = writer.join();
+
+    match status.code() {
+        Some(0) => println!("(press any key to go back)"),
+        Some(code) => println!("(exited with status {}; press any key to go back)", code),
+        None => println!("(terminated by a signal; press any key to go back)"),
     }
+    enable_raw_mode()?;
+    while !matches!(read()?, Event::Key(_)) {}
 
-    pub fn prev(&mut self) {
-        if self.query.is_empty() {
-            *self.message.borrow_mut() = Some("search query is not set".to_string());
-            self.needs_update.set(true);
-            return;
+    Ok(())
+}
+
+/// Joins `lines` into a text, ending each with a newline.
+fn join_lines(lines: &[String]) -> String {
+    lines
+        .iter()
+        .flat_map(|line| [line.as_str(), "\n"])
+        .collect()
+}
+
+/// The screens of the files given on the command line, so that each file keeps its own position
+/// and query. The screen of the file shown is taken out to `scr` of `run()` and put back when
+/// another file is shown.
+struct Files {
+    screens: Vec<Option<Screen>>,
+    current: usize,
+}
+
+impl Files {
+    /// Returns the screen of the first file, which is `scr` if it is shown.
+    fn first_screen<'a>(&'a mut self, scr: &'a mut Screen) -> &'a mut Screen {
+        match self.screens.first_mut().and_then(Option::as_mut) {
+            Some(first) if self.current != 0 => first,
+            _ => scr,
         }
+    }
+}
 
-        match self
-            .lines
-            .iter()
-            .enumerate()
-            .take(self.current_top as usize)
-            .rev()
-            .find(|(_, line)| line.contains(&self.query))
-        {
-            Some((line, _)) => {
-                self.current_top = line as isize;
-                self.fix_current_top();
-            }
-            None => {
-                *self.message.borrow_mut() = Some(format!("failed to find `{}`", self.query));
+/// Shows the file `idx` of `options.file_paths` in `scr`, reading it if it is shown for the first
+/// time. The search query and the history are carried over to it.
+fn switch_file(scr: &mut Screen, files: &mut Files, idx: usize, options: &Options) {
+    let path = &options.file_paths[idx];
+    let mut next = match files.screens[idx].take() {
+        Some(next) => next,
+        None => match open_file(path, options) {
+            Ok(mut next) => {
+                next.get_query_mut().set(scr.get_query().to_string());
+                next
             }
-        }
+            Err(e) => return scr.show_message(format!("{:#}", e)),
+        },
+    };
+    // The terminal may have been resized while the file was hidden.
+    next.resized();
+    next.set_search_history(scr.search_history().to_vec());
+    next.redraw();
+    files.screens[files.current] = Some(replace(scr, next));
+    files.current = idx;
+    if !scr.has_message() {
+        scr.show_message(format!(
+            "{} ({}/{})",
+            path.display(),
+            idx + 1,
+            options.file_paths.len()
+        ));
     }
+}
+
+/// Describes each file for the list shown with `=`: the path, the size and the line at the top of
+/// the screen if the file has been shown.
+fn file_list_items(scr: &Screen, files: &Files, options: &Options) -> Vec<String> {
+    let paths: Vec<_> = options
+        .file_paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    let width = |s: &str| s.chars().map(|ch| ch.width().unwrap_or(0)).sum::<usize>();
+    let path_width = paths.iter().map(|path| width(path)).max().unwrap_or(0);
+    paths
+        .iter()
+        .zip(&options.file_paths)
+        .enumerate()
+        .map(|(idx, (name, path))| {
+            let size = fs::metadata(path).map_or("-".to_string(), |m| format_size(m.len()));
+            let screen = if idx == files.current {
+                Some(scr)
+            } else {
+                files.screens[idx].as_ref()
+            };
+            let position = screen.map_or(String::new(), |screen| {
+                format!("line {}", screen.top_line_number())
+            });
+            let padding = " ".repeat(path_width - width(name));
+            format!("{}{}  {:>6}  {}", name, padding, size, position)
+        })
+        .collect()
+}
 
-    pub fn next(&mut self) {
-        if self.query.is_empty() {
-            *self.message.borrow_mut() = Some("search query is not set".to_string());
-            self.needs_update.set(true);
-            return;
+/// Formats a number of bytes briefly, like `ls -h`.
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["", "K", "M", "G"] {
+        if size < 1024.0 {
+            return match unit {
+                "" => format!("{}", bytes),
+                _ if size < 10.0 => format!("{:.1}{}", size, unit),
+                _ => format!("{:.0}{}", size, unit),
+            };
         }
+        size /= 1024.0;
+    }
 
-        match self
-            .lines
-            .iter()
-            .enumerate()
-            .skip(self.current_top as usize + 1)
-            .find(|(_, line)| line.contains(&self.query))
-        {
-            Some((line, _)) => {
-                self.current_top = line as isize;
-                self.fix_current_top();
+    format!("{:.0}T", size)
+}
+
+/// Reads the whole file at `path`, decompressing it if needed, and makes a screen showing it.
+fn open_file(path: &Path, options: &Options) -> anyhow::Result<Screen> {
+    let decompressor = decompressor_for(path);
+    let (mut input, encoding, is_binary) = match decompressor {
+        Some(program) => {
+            let mut input = String::new();
+            for chunk in spawn_decompressor_reader(program, path)? {
+                input.push_str(
+                    &chunk.with_context(|| format!("failed to read `{}`", path.display()))?,
+                );
             }
+            (input, None, false)
+        }
+        None => read_file(path, options)?,
+    };
+    let format_error = format_input(&mut input, options.json, options.columns);
+
+    let (width, height) = term_size::dimensions_stdout().unwrap_or((80, 24));
+    let mut scr = Screen::new(width, height, input);
+    configure_screen(&mut scr, options);
+    if decompressor.is_none() {
+        scr.set_path(path.to_path_buf());
+    }
+    scr.set_encoding(encoding);
+    show_input_notes(&mut scr, options, format_error, is_binary, encoding);
+
+    Ok(scr)
+}
+
+/// Reads the file at `path` as text, in its encoding. Returns the text with the encoding if it
+/// isn't UTF-8, and whether the file looks binary.
+fn read_file(
+    path: &Path,
+    options: &Options,
+) -> anyhow::Result<(String, Option<&'static str>, bool)> {
+    let input = fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    Ok(match decode(&input, options.encoding.as_deref())? {
+        Some((text, encoding)) => (text, Some(encoding), false),
+        None => (
+            String::from_utf8_lossy(&input).into_owned(),
+            None,
+            looks_binary(&input),
+        ),
+    })
+}
+
+/// Formats `input` as asked by `--json` and `--columns`. Returns why it couldn't be if so.
+fn format_input(input: &mut String, json: bool, columns: bool) -> Option<String> {
+    let mut format_error = None;
+    if json {
+        match pretty_print_json(input) {
+            Ok(pretty) => *input = pretty,
+            Err(e) => format_error = Some(format!("not formatted as JSON: {}", e)),
+        }
+    }
+    if columns {
+        match align_columns(input) {
+            Some(aligned) => *input = aligned,
             None => {
-                *self.message.borrow_mut() = Some(format!("failed to find `{}`", self.query));
+                format_error = Some("columns not aligned: the input is not a table".to_string())
             }
         }
     }
 
-    pub fn draw(&self) {
-        if !self.needs_update.get() {
-            return;
+    format_error
+}
+
+/// Applies the options about how the contents are shown to `scr`.
+fn configure_screen(scr: &mut Screen, options: &Options) {
+    scr.set_cr_mode(options.cr_mode);
+    // Aligned columns are useless once wrapped; scroll horizontally instead.
+    scr.set_wrap_mode(options.wrap_mode.unwrap_or(if options.columns {
+        WrapMode::None
+    } else {
+        WrapMode::Char
+    }));
+    scr.set_scrolloff(options.scrolloff);
+    scr.set_wrap_search(options.wrap_search);
+    scr.set_filter_context(options.filter_context);
+    scr.set_case_mode(options.case_mode);
+    scr.set_max_rows(options.max_rows);
+    if let Some(tab_width) = options.tab_width {
+        scr.set_tab_width(tab_width);
+    }
+    scr.set_wide_gap_marker(options.wide_gap_marker);
+    scr.set_wrap_marker(options.wrap_marker.clone());
+    scr.set_keep_indent(options.keep_indent);
+    scr.set_scroll_amount(MoveUnit::Line, options.scroll_lines);
+    scr.set_scroll_amount(MoveUnit::HalfPage, options.half_page_lines);
+    scr.set_scroll_amount(MoveUnit::Page, options.page_lines);
+    scr.set_page_overlap(options.page_overlap);
+    if options.scrollbar {
+        scr.toggle_scrollbar();
+    }
+    scr.set_line_numbers(options.line_numbers);
+    scr.set_sticky_header(options.sticky_header);
+    scr.set_highlight_color(options.highlight_color);
+    scr.set_squeeze_blank_lines(options.squeeze_blank_lines);
+    scr.set_raw_control_chars(options.raw_control_chars);
+    if options.json || options.columns {
+        // The file is formatted again when it is reloaded.
+        let (json, columns) = (options.json, options.columns);
+        scr.set_formatter(move |text| {
+            let mut text = text.to_string();
+            format_input(&mut text, json, columns);
+            text
+        });
+    }
+}
+
+/// Tells about the input in the prompt: why it couldn't be formatted, that it is binary, which is
+/// then shown as a hex dump, or the encoding guessed for it.
+fn show_input_notes(
+    scr: &mut Screen,
+    options: &Options,
+    format_error: Option<String>,
+    is_binary_file: bool,
+    encoding: Option<&str>,
+) {
+    if let Some(message) = format_error {
+        scr.show_message(message);
+    }
+
+    // The text of a binary file is rarely what the user wants to read.
+    if options.hex || is_binary_file {
+        scr.toggle_hex_dump();
+        if !options.hex {
+            scr.show_message("this looks like a binary file; H shows the text");
+        }
+    } else if scr.get_contents().contains('\0') {
+        scr.show_message("this looks like a binary file");
+    } else if let Some(encoding) = encoding.filter(|_| options.encoding.is_none()) {
+        scr.show_message(format!("read as {}", encoding));
+    }
+}
+
+/// Runs `command` and reads what it prints to stdout and stderr (unless `no_stderr`) in a
+/// background thread, like `spawn_stdin_reader()`. A note is added at the end if the command
+/// failed, and its exit status is set to `exit_code`.
+fn spawn_command_reader(
+    command: &ChildCommand,
+    no_stderr: bool,
+    exit_code: Arc<OnceLock<i32>>,
+) -> anyhow::Result<Receiver<io::Result<String>>> {
+    let process = match command {
+        ChildCommand::Shell(command) => shell_command(command),
+        ChildCommand::Program(args) => {
+            let mut program = Command::new(&args[0]);
+            program.args(&args[1..]);
+            program
         }
+    };
+
+    spawn_process_reader(process, &command.name(), no_stderr, exit_code)
+}
 
-        let stdout = stdout();
-        let mut stdout = stdout.lock();
+/// Returns the command running `command` in the shell, `sh` or `cmd` on Windows.
+fn shell_command(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
 
-        let start = self.current_top as usize;
-        let end = min(self.lines.len(), start + self.contents_height());
-        debug_assert!(end <= self.lines.len());
+/// The programs decompressing the files starting with these magic bytes to stdout with `-dc`.
+const DECOMPRESSORS: &[(&[u8], &str)] = &[
+    (b"\x1f\x8b", "gzip"),
+    (b"BZh", "bzip2"),
+    (b"\xfd7zXZ\x00", "xz"),
+    (b"\x28\xb5\x2f\xfd", "zstd"),
+];
 
-        // build line segments
-        let line_segments: Vec<_> = self.lines[start..end]
-            .iter()
-            .map(|line| {
-                let mut segments = vec![];
-                if self.query.is_empty() {
-                    segments.push(line.as_str().stylize());
-                } else {
-                    let mut curr_idx = 0;
-                    for (next_idx, substr) in line.match_indices(&self.query) {
-                        let normal = &line[curr_idx..next_idx];
-                        segments.push(normal.stylize());
-                        segments.push(substr.with(Color::Red));
-                        curr_idx = next_idx + substr.len();
-                    }
-                    segments.push((&line[curr_idx..]).stylize());
-                };
-                segments
-            })
-            .collect();
-
-        // enqueue commands
-        queue!(stdout, Hide, MoveTo(0, 0)).unwrap();
-        for segments in line_segments {
-            stdout.queue(Clear(ClearType::CurrentLine)).unwrap();
-            for segment in segments {
-                stdout.queue(PrintStyledContent(segment)).unwrap();
+/// Returns the program decompressing the file at `path` if it is compressed, by its first bytes.
+fn decompressor_for(path: &Path) -> Option<&'static str> {
+    let mut magic = [0; 6];
+    let len = fs::File::open(path)
+        .and_then(|mut file| file.read(&mut magic))
+        .ok()?;
+    DECOMPRESSORS
+        .iter()
+        .find(|(bytes, _)| magic[..len].starts_with(bytes))
+        .map(|&(_, program)| program)
+}
+
+/// Runs `program` to decompress the file at `path` and reads its output in the background, like
+/// `spawn_command_reader()`.
+fn spawn_decompressor_reader(
+    program: &str,
+    path: &Path,
+) -> anyhow::Result<Receiver<io::Result<String>>> {
+    let mut decompressor = Command::new(program);
+    decompressor.arg("-dc").arg(path);
+
+    spawn_process_reader(decompressor, program, false, Arc::default())
+}
+
+/// Runs `command` and reads its output in the background. `name` is how errors refer to it. Its
+/// stderr is thrown away if `no_stderr`. Its exit status is set to `exit_code` when it exits.
+fn spawn_process_reader(
+    mut command: Command,
+    name: &str,
+    no_stderr: bool,
+    exit_code: Arc<OnceLock<i32>>,
+) -> anyhow::Result<Receiver<io::Result<String>>> {
+    // Both stdout and stderr go to the same pipe so that errors show up where they happened.
+    let (reader, writer) = io::pipe().context("failed to create a pipe")?;
+    let stderr = if no_stderr {
+        Stdio::null()
+    } else {
+        writer
+            .try_clone()
+            .context("failed to create a pipe")?
+            .into()
+    };
+    command.stdin(Stdio::null()).stdout(writer).stderr(stderr);
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("failed to run `{}`", name))?;
+    // The pipe is closed only after every copy of the write end is dropped, including the ones
+    // held by `command`.
+    drop(command);
+
+    // The child is reaped once its output ends.
+    Ok(spawn_reader(reader, move || {
+        let status = child.wait();
+        if let Ok(status) = &status {
+            let _ = exit_code.set(status.code().unwrap_or(EXIT_FAILURE.into()));
+        }
+        let note = match status {
+            Ok(status) if status.success() => return None,
+            Ok(status) => match status.code() {
+                Some(code) => format!("(command exited with status {})", code),
+                None => "(command was terminated by a signal)".to_string(),
+            },
+            Err(e) => format!("(failed to wait for the command: {})", e),
+        };
+        Some(note)
+    }))
+}
+
+/// Reads stdin in a background thread, sending it in chunks as it arrives. The channel is closed at
+/// the end of the input.
+fn spawn_stdin_reader() -> Receiver<io::Result<String>> {
+    spawn_reader(stdin(), || None)
+}
+
+/// Reads `input` in a background thread, sending it in chunks as it arrives. At the end of the
+/// input, the note returned by `finish`, if any, is sent on a line of its own and the channel is
+/// closed.
+fn spawn_reader(
+    mut input: impl Read + Send + 'static,
+    finish: impl FnOnce() -> Option<String> + Send + 'static,
+) -> Receiver<io::Result<String>> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        let mut pending = vec![];
+        // Whether anything has been read and the last of it ended a line.
+        let mut at_line_start = true;
+        loop {
+            let len = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            pending.extend_from_slice(&buf[..len]);
+            at_line_start = buf[len - 1] == b'\n';
+
+            // A multi-byte character may be split across reads; keep its first half until the
+            // rest arrives. Other invalid bytes are replaced with U+FFFD.
+            let complete_len = pending.len() - incomplete_suffix_len(&pending);
+            if complete_len == 0 {
+                continue;
             }
 
-            // seems bit flicker-less (why?)
-            if cfg!(windows) {
-                stdout.queue(Print('\n')).unwrap();
-            } else {
-                stdout.queue(Print("\r\n")).unwrap();
+            let rest = pending.split_off(complete_len);
+            let chunk =
+                String::from_utf8_lossy(&std::mem::replace(&mut pending, rest)).into_owned();
+            if tx.send(Ok(chunk)).is_err() {
+                // The pager has quit.
+                return;
             }
         }
 
-        let message = self
-            .message
-            .borrow()
-            .as_ref()
-            .cloned()
-            .unwrap_or_else(|| self.query.clone());
-        queue!(
-            stdout,
-            MoveTo(0, self.contents_height() as u16),
-            Clear(ClearType::CurrentLine),
-            Print(format_args!(
-                "{}{}",
-                if self.query_mode { '/' } else { ':' },
-                message
-            )),
-            Show
-        )
-        .unwrap();
+        if !pending.is_empty() {
+            let _ = tx.send(Ok(String::from_utf8_lossy(&pending).into_owned()));
+        }
+        if let Some(note) = finish() {
+            let separator = if at_line_start { "" } else { "\n" };
+            let _ = tx.send(Ok(format!("{}{}\n", separator, note)));
+        }
+    });
+
+    rx
+}
+
+/// Returns the length of the incomplete UTF-8 sequence at the end of `bytes`, which may be
+/// completed by the next read.
+fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+    for (idx, &byte) in bytes.iter().rev().take(3).enumerate() {
+        let expected_len = match byte {
+            0x80..=0xbf => continue,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => return 0,
+        };
+        return if idx + 1 < expected_len { idx + 1 } else { 0 };
+    }
 
-        *self.message.borrow_mut() = None;
-        stdout.flush().unwrap();
+    0
+}
 
-        self.needs_update.set(false);
+/// Appends all the input received so far to the screen. Returns `false` when there is no more
+/// input to wait for.
+fn receive_input(scr: &mut Screen, rx: &Receiver<io::Result<String>>) -> bool {
+    loop {
+        match rx.try_recv() {
+            Ok(Ok(chunk)) => scr.append(&chunk),
+            Ok(Err(e)) => scr.show_message(format!("failed to read input: {}", e)),
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+        }
+    }
+}
+
+/// Reads commands line by line from the file descriptor `fd` in a background thread. The channel
+/// is closed when the other end is.
+#[cfg(unix)]
+fn spawn_control_reader(fd: i32) -> anyhow::Result<Receiver<String>> {
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::os::unix::io::FromRawFd;
+
+    // The standard streams are used by the pager itself, and the file would close them.
+    if (0..=2).contains(&fd) {
+        bail!("--control-fd can't be {}, which is a standard stream", fd);
+    }
+    // SAFETY: `fcntl` only asks about the descriptor, which may not be open.
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        bail!("file descriptor {} is not open", fd);
     }
+    // SAFETY: the descriptor is open, and it was passed to us to read the commands from; the file
+    // owns it from here on.
+    let file = unsafe { File::from_raw_fd(fd) };
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            if tx.send(line).is_err() {
+                // The pager has quit.
+                return;
+            }
+        }
+    });
 
-    fn contents_height(&self) -> usize {
-        // The last line is for prompt `:`
-        self.height.saturating_sub(1)
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+fn spawn_control_reader(_fd: i32) -> anyhow::Result<Receiver<String>> {
+    bail!("--control-fd is only supported on Unix")
+}
+
+/// Runs all the control commands received so far. Returns `false` when no more commands can
+/// arrive.
+fn receive_control(scr: &mut Screen, rx: &Receiver<String>) -> bool {
+    loop {
+        match rx.try_recv() {
+            Ok(command) => run_control_command(scr, &command),
+            Err(TryRecvError::Empty) => return true,
+            Err(TryRecvError::Disconnected) => return false,
+        }
     }
+}
 
-    fn recalc_lines(&mut self) {
-        self.lines = LineBreaker::new(self.width, &self.contents).collect();
-        self.needs_update.set(true);
+/// Runs a command from `--control-fd`: `goto N`, `search PATTERN`, `top` or `bottom`. Anything
+/// else is ignored.
+fn run_control_command(scr: &mut Screen, command: &str) {
+    let (name, arg) = match command.trim().split_once(' ') {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command.trim(), ""),
+    };
+    match name {
+        "goto" => {
+            if let Ok(line_number) = arg.parse() {
+                scr.goto_line(line_number);
+            }
+        }
+        "search" if !arg.is_empty() => {
+            scr.get_query_mut().set(arg.to_string());
+            scr.next();
+        }
+        "top" => scr.up_by(MoveUnit::Entire, 1),
+        "bottom" => scr.down_by(MoveUnit::Entire, 1),
+        _ => {}
     }
+}
+
+/// The system clipboard, opened on first use. It is kept open afterwards because on some platforms
+/// such as X11 the copied text is served by this process and disappears when it is closed.
+#[derive(Default)]
+struct Clipboard {
+    #[cfg(feature = "clipboard")]
+    inner: Option<arboard::Clipboard>,
+}
+
+impl Clipboard {
+    #[cfg(feature = "clipboard")]
+    fn copy(&mut self, text: String) -> anyhow::Result<()> {
+        let clipboard = match &mut self.inner {
+            Some(clipboard) => clipboard,
+            None => self.inner.insert(arboard::Clipboard::new()?),
+        };
+        clipboard.set_text(text)?;
 
-    fn scroll(&mut self, amount: isize) {
-        self.current_top = self.current_top.saturating_add(amount);
-        self.fix_current_top();
-        self.needs_update.set(true);
+        Ok(())
     }
 
-    fn fix_current_top(&mut self) {
-        let max_top = self.lines.len().saturating_sub(self.contents_height());
-        self.current_top = self.current_top.clamp(0, max_top as isize);
-        self.needs_update.set(true);
+    #[cfg(not(feature = "clipboard"))]
+    fn copy(&mut self, _text: String) -> anyhow::Result<()> {
+        bail!("pag is built without clipboard support")
     }
 }
 
-struct LineBreaker {
-    contents: Vec<char>,
-    curr_idx: usize,
-    width: usize,
+/// Pretty-prints `input` as JSON, or fails if it isn't valid JSON.
+#[cfg(feature = "json")]
+fn pretty_print_json(input: &str) -> anyhow::Result<String> {
+    let value: serde_json::Value = serde_json::from_str(input)?;
+    Ok(serde_json::to_string_pretty(&value)? + "\n")
 }
 
-impl LineBreaker {
-    pub fn new(width: usize, contents: &str) -> Self {
-        Self {
-            contents: contents.chars().collect(),
-            curr_idx: 0,
-            width,
+#[cfg(not(feature = "json"))]
+fn pretty_print_json(_input: &str) -> anyhow::Result<String> {
+    bail!("pag is built without JSON support")
+}
+
+/// Restores the terminal before a panic message is printed, so that the message is readable and
+/// the terminal is usable after a crash. The `defer!` guards would restore it only after the
+/// message is printed in raw mode, on the alternate screen.
+fn install_panic_hook(alt_screen: bool) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // Errors are ignored; the message is more important.
+        let _ = execute!(stdout(), DisableMouseCapture);
+        let _ = disable_raw_mode();
+        if alt_screen {
+            let _ = execute!(stdout(), LeaveAlternateScreen);
+        } else {
+            // Print the message below the last view.
+            let rows = size().map_or(1, |(_, rows)| rows);
+            let _ = execute!(stdout(), MoveTo(0, rows.saturating_sub(1)), Print('\n'));
+        }
+        default_hook(info);
+    }));
+}
+
+/// Suspends the pager and opens `path` in the user's editor, placing the cursor at `line_number`
+/// (1-based). The terminal is restored when the editor exits. `alt_screen` is whether the pager is
+/// on the alternate screen.
+fn open_in_editor(path: &Path, line_number: usize, alt_screen: bool) -> anyhow::Result<()> {
+    let editor = var_os("VISUAL")
+        .or_else(|| var_os("EDITOR"))
+        .and_then(|editor| editor.into_string().ok())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    let mut editor_args = editor.split_whitespace();
+    let program = editor_args
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("$EDITOR is empty"))?;
+
+    let mut command = Command::new(program);
+    command.args(editor_args);
+    // notepad doesn't understand `+N`, but most editors on Unix do.
+    if !program.eq_ignore_ascii_case("notepad") {
+        command.arg(format!("+{}", line_number));
+    }
+    command.arg(path);
+
+    disable_raw_mode()?;
+    if alt_screen {
+        execute!(stdout(), LeaveAlternateScreen)?;
+    }
+    defer! {
+        if alt_screen {
+            execute!(stdout(), EnterAlternateScreen).unwrap();
         }
+        enable_raw_mode().unwrap();
     }
+
+    command.status()?;
+
+    Ok(())
 }
 
-impl Iterator for LineBreaker {
-    type Item = String;
+/// Returns the path of the file remembering the last search query between runs.
+fn last_search_path() -> Option<PathBuf> {
+    let cache_dir = if cfg!(windows) {
+        var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut line = String::new();
-        let mut curr_width = 0;
-        while self.curr_idx < self.contents.len() {
-            let ch = self.contents[self.curr_idx];
-            self.curr_idx += 1;
+    cache_dir.map(|dir| dir.join("pag").join("lastsearch"))
+}
 
-            if ch == '\r' {
-                continue;
-            }
+/// The number of queries kept in the search history between runs.
+const SEARCH_HISTORY_LEN: usize = 100;
 
-            if ch == '\n' {
-                return Some(line);
-            }
+/// Returns the path of the file keeping the search history, next to the last search query.
+fn search_history_path() -> Option<PathBuf> {
+    last_search_path().map(|path| path.with_file_name("searchhistory"))
+}
 
-            let ch_width = ch.width().unwrap_or(1);
-            if curr_width + ch_width > self.width {
-                self.curr_idx -= 1;
-                return Some(line);
-            }
+fn load_search_history() -> Vec<String> {
+    search_history_path()
+        .and_then(|path| read_to_string(path).ok())
+        .map(|history| history.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
 
-            curr_width += ch_width;
-            line.push(ch);
+fn save_search_history(history: &[String]) {
+    if let Some(path) = search_history_path() {
+        if let Some(dir) = path.parent() {
+            let _ = create_dir_all(dir);
         }
+        let start = history.len().saturating_sub(SEARCH_HISTORY_LEN);
+        let _ = write(path, history[start..].join("\n"));
+    }
+}
+
+fn load_last_search() -> String {
+    // A missing or unreadable file just means there is no previous query.
+    last_search_path()
+        .and_then(|path| read_to_string(path).ok())
+        .map(|query| query.trim_end_matches(&['\r', '\n'][..]).to_string())
+        .unwrap_or_default()
+}
 
-        Some(line).filter(|s| !s.is_empty())
+fn save_last_search(query: &str) {
+    // Failing to remember the query is not worth bothering the user about.
+    if let Some(path) = last_search_path() {
+        if let Some(dir) = path.parent() {
+            let _ = create_dir_all(dir);
+        }
+        let _ = write(path, query);
     }
 }
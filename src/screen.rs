@@ -0,0 +1,3290 @@
+use crate::encoding::decode;
+use crate::hex_dump::{hex_dump, hex_dump_at, BYTES_PER_LINE};
+use crate::line_breaker::{
+    caret_notation, cluster_width, csi_ranges, display_units, hyperlink_ranges, is_hyperlink,
+    is_sgr, marked_width, overstrike_ranges, shown_text, source_line_at, source_line_texts,
+    str_width, CrMode, LineBreaker, WrapMode, DEFAULT_TAB_WIDTH,
+};
+use crate::line_edit::LineEdit;
+use crossterm::cursor::{Hide, MoveTo, RestorePosition, SavePosition, Show};
+use crossterm::queue;
+use crossterm::style::{
+    Attribute, Color, ContentStyle, Print, PrintStyledContent, StyledContent, Stylize,
+};
+use crossterm::terminal::{Clear, ClearType};
+use crossterm::QueueableCommand;
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{metadata, read, File};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::mem::{replace, take};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthChar;
+
+/// The number of columns for the line numbers in the gutter, like `less -N`. Another column
+/// separates them from the lines.
+const LINE_NUMBER_WIDTH: usize = 7;
+
+/// The number of positions kept in the jump history.
+const JUMP_HISTORY_LEN: usize = 100;
+
+/// The labels put on the rows on the screen in the label mode, from the top.
+const ROW_LABELS: &str = "123456789abcdefghijklmnopqrstuvwxyz";
+
+/// The distance to scroll by.
+pub enum MoveUnit {
+    Line,
+    HalfPage,
+    Page,
+    Entire,
+}
+
+/// Whether searching tells uppercase letters from lowercase ones.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaseMode {
+    Sensitive,
+    Insensitive,
+    /// Ignore the case unless the query has an uppercase letter, like `less -i`.
+    Smart,
+}
+
+/// What the gutter on the left shows.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineNumbers {
+    Hidden,
+    /// The number of the source line on its first row.
+    Absolute,
+    /// How many rows below the top of the screen each row is, i.e. the count for `j` which
+    /// scrolls it to the top.
+    Relative,
+}
+
+/// A search match jumped to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Match {
+    line: usize,
+    /// The bytes of the match in the line.
+    range: Range<usize>,
+    /// `current_top` right after the jump. The next search continues from the match as long as the
+    /// screen hasn't been scrolled since.
+    top: isize,
+}
+
+/// The pane not focused while the screen is split. It shows the same contents at its own position.
+struct Split {
+    /// The (0-based) source line at the top of the pane.
+    top_line: usize,
+    /// Whether the focused pane is the upper one, i.e. this pane is below it.
+    focus_upper: bool,
+}
+
+/// A function formatting the text of the file, e.g. pretty-printing JSON.
+type Formatter = Box<dyn Fn(&str) -> String>;
+
+/// The positions (the line and the byte offset) of all the matches of a query in order, with the
+/// query and the number of lines they were searched in.
+type MatchPositions = (String, usize, Vec<(usize, usize)>);
+
+/// Which source lines are shown while filtering.
+struct Filter {
+    regex: Regex,
+    /// Whether the lines matching `regex` are hidden instead, like `grep -v`.
+    inverted: bool,
+    /// The number of lines shown before and after each line passing the filter, like `grep -C`.
+    context: usize,
+    /// The last source line wrapped so far which passed the filter.
+    last_passed: Option<usize>,
+    /// The last source line wrapped so far which is shown, passing the filter or as its context.
+    last_shown: Option<usize>,
+}
+
+impl Filter {
+    fn passes(&self, line: &str) -> bool {
+        self.regex.is_match(&shown_text(line).0) != self.inverted
+    }
+
+    /// Returns whether the source line `source_line` at the beginning of `rest` is shown. The
+    /// source lines are asked in order.
+    fn shows(&mut self, source_line: usize, rest: &str, cr_mode: CrMode) -> bool {
+        let mut lines = source_line_texts(rest, cr_mode);
+        let shown = if lines.next().is_some_and(|line| self.passes(line)) {
+            self.last_passed = Some(source_line);
+            true
+        } else {
+            self.last_passed
+                .is_some_and(|passed| source_line <= passed + self.context)
+                || lines.take(self.context).any(|line| self.passes(line))
+        };
+        if shown {
+            self.last_shown = Some(source_line);
+        }
+
+        shown
+    }
+
+    /// Forgets the lines wrapped so far, to wrap them again from the beginning.
+    fn reset(&mut self) {
+        self.last_passed = None;
+        self.last_shown = None;
+    }
+}
+
+/// How a part of a line is highlighted as a search match.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Highlight {
+    None,
+    Match,
+    /// The match last jumped to.
+    Current,
+}
+
+/// The styled segments of the lines drawn last time, so that scrolling doesn't search and style
+/// the lines still on the screen again.
+#[derive(Default)]
+struct SegmentCache {
+    /// The query the segments were highlighted for.
+    query: String,
+    /// The match shown as the current one in the segments.
+    current_match: Option<(usize, Range<usize>)>,
+    segments: HashMap<usize, Vec<StyledContent<String>>>,
+}
+
+/// The state of the pager: the contents wrapped to the terminal width, the scroll position and the
+/// search query.
+pub struct Screen {
+    width: usize,
+    /// The height of the focused pane, which is the whole terminal unless it is split.
+    height: usize,
+    /// The height of the whole terminal.
+    screen_height: usize,
+    /// The terminal row where the focused pane starts.
+    top_row: usize,
+    split: Option<Split>,
+    path: Option<PathBuf>,
+    /// The number of bytes of the file read into the contents, where following it continues.
+    file_len: u64,
+    /// When the file was last modified as of reading it, to tell whether it has changed since.
+    file_modified: Option<SystemTime>,
+    /// Whether the screen stays at the end, showing new input as it arrives.
+    following: bool,
+    /// The source line to scroll back to while the contents are read again, and the number of
+    /// lines read so far.
+    top_to_restore: Option<(usize, usize)>,
+    contents: String,
+    /// The encoding of the file if it isn't UTF-8, to read it again.
+    encoding: Option<&'static str>,
+    /// Formats the text of the file again when it is read again, e.g. pretty-prints JSON.
+    formatter: Option<Formatter>,
+    /// The text of the contents while their hex dump is shown instead.
+    hidden_text: Option<String>,
+    showing_hex_dump: bool,
+    lines: Vec<String>,
+    source_lines: Vec<usize>,
+    /// Where wrapping continues: the byte offset and the number of the first source line not in
+    /// `lines` yet. Once everything is wrapped, they point to the last source line instead, which
+    /// is wrapped again when more input arrives.
+    last_line_start: usize,
+    last_source_line: usize,
+    /// Whether the whole contents are in `lines`. The contents are wrapped lazily, only as far as
+    /// needed, so that a large file is shown without wrapping all of it first.
+    wrapped_all: bool,
+    /// The index of the first line on the screen below the sticky header.
+    current_top: isize,
+    /// The first column shown when lines are not wrapped.
+    left_column: usize,
+    /// The (0-based) source lines where the selection starts and ends. The end may be above the
+    /// start.
+    selection_start: Option<usize>,
+    selection_end: Option<usize>,
+    /// The source lines marked with `m`, by the letter of the mark.
+    marks: HashMap<char, usize>,
+    /// The source line which was at the top of the screen before the last jump, where `''` goes
+    /// back to.
+    jump_origin: Option<usize>,
+    /// The source lines at the top of the screen before the jumps, oldest first, like the jump
+    /// list of Vim.
+    jump_history: Vec<usize>,
+    /// The entry of `jump_history` gone back to with `Ctrl-O`. `jump_history.len()` unless going
+    /// through the history.
+    jump_pos: usize,
+    /// The number of source lines pinned at the top of the screen.
+    sticky_header: usize,
+    /// The number of lines kept above a line jumped to, e.g. a search match.
+    scrolloff: usize,
+    /// The last search match jumped to.
+    last_match: Option<Match>,
+    /// The top line and the last match when the query started to be typed, where the incremental
+    /// search starts from and where cancelling the query goes back to.
+    query_origin: Option<(isize, Option<Match>)>,
+    /// Whether `next` and `prev` continue from the other end when there are no more matches.
+    wrap_search: bool,
+    case_mode: CaseMode,
+    page_overlap: usize,
+    /// The number of lines to scroll by for each unit; `None` means the default, which depends on
+    /// the screen height for the pages.
+    line_amount: Option<usize>,
+    half_page_amount: Option<usize>,
+    page_amount: Option<usize>,
+    show_nonprinting: bool,
+    /// Whether control characters are printed as they are rather than in the caret notation.
+    raw_control_chars: bool,
+    /// Whether spaces, tabs and line ends are marked, like `cat -A`.
+    show_whitespace: bool,
+    /// The color of search matches.
+    highlight_color: Color,
+    cr_mode: CrMode,
+    wrap_mode: WrapMode,
+    tab_width: usize,
+    /// The wrap mode `toggle_wrap` goes back to after chopping the lines.
+    last_wrap_mode: WrapMode,
+    max_rows: Option<usize>,
+    /// The character filling the gap left at the right edge when a wide character is wrapped to the
+    /// next row.
+    wide_gap_marker: Option<char>,
+    /// The marker at the beginning of each continued row of a wrapped line.
+    wrap_marker: Option<String>,
+    /// Whether continued rows are indented like the beginning of their source line.
+    keep_indent: bool,
+    /// Whether a run of blank lines is shown as a single blank line.
+    squeeze_blank_lines: bool,
+    query_mode: bool,
+    /// Whether a command is being typed at the `:` prompt.
+    command_mode: bool,
+    /// Whether a pattern to filter the lines with is being typed at the `&` prompt.
+    filter_mode: bool,
+    /// Whether the rows on the screen are labeled for jumping to them.
+    label_mode: bool,
+    /// The items of the list shown over the contents, e.g. the files to choose from, and the
+    /// selected one.
+    overlay: Option<(Vec<String>, usize)>,
+    /// Whether the rightmost column shows where the screen is in the contents.
+    show_scrollbar: bool,
+    line_numbers: LineNumbers,
+    command: LineEdit,
+    filter_input: LineEdit,
+    /// Only the source lines passing this are shown. The contents are kept as they are, so that
+    /// all the lines come back when the filter is cleared.
+    filter: Option<Filter>,
+    /// The number of lines of context shown around the lines passing the filter.
+    filter_context: usize,
+    /// The rows separating the groups of lines not next to each other in the contents when
+    /// filtering with context, in order. They are drawn as `--`, like `grep -C`.
+    separator_rows: Vec<usize>,
+    /// The number of columns before the contents of each row in `lines`, for the wrap marker and
+    /// the indentation of continued rows.
+    row_indents: Vec<usize>,
+    query: LineEdit,
+    /// The queries searched for so far, oldest first.
+    search_history: Vec<String>,
+    /// The entry of `search_history` shown in the prompt, and the query which was being typed
+    /// before browsing the history. `None` while typing a new query.
+    history_pos: Option<(usize, String)>,
+    loading: Option<usize>,
+    message: RefCell<Option<String>>,
+    needs_update: Cell<bool>,
+    /// Whether the last row is kept for the prompt. When hidden, the prompt is only shown over the
+    /// contents while there is something to show, e.g. a message or a query being typed.
+    show_status_line: bool,
+    segment_cache: RefCell<SegmentCache>,
+    /// The matches of the query, counted for the position of the current match.
+    match_positions: RefCell<Option<MatchPositions>>,
+    /// The regular expression compiled from the query, and the query it was compiled from.
+    query_regex: RefCell<Option<(String, Regex)>>,
+}
+
+impl Screen {
+    pub fn new(width: usize, height: usize, contents: String) -> Self {
+        // The screen needs at least one column to wrap lines and one row for the prompt.
+        let mut scr = Self {
+            width: width.max(1),
+            height: height.max(1),
+            screen_height: height.max(1),
+            top_row: 0,
+            split: None,
+            path: None,
+            file_len: 0,
+            file_modified: None,
+            following: false,
+            top_to_restore: None,
+            contents,
+            encoding: None,
+            formatter: None,
+            hidden_text: None,
+            showing_hex_dump: false,
+            lines: vec![],
+            source_lines: vec![],
+            last_line_start: 0,
+            last_source_line: 0,
+            wrapped_all: false,
+            current_top: 0,
+            left_column: 0,
+            selection_start: None,
+            selection_end: None,
+            marks: HashMap::new(),
+            jump_origin: None,
+            jump_history: vec![],
+            jump_pos: 0,
+            sticky_header: 0,
+            scrolloff: 0,
+            last_match: None,
+            query_origin: None,
+            wrap_search: false,
+            case_mode: CaseMode::Sensitive,
+            page_overlap: 1,
+            line_amount: None,
+            half_page_amount: None,
+            page_amount: None,
+            show_nonprinting: false,
+            raw_control_chars: false,
+            show_whitespace: false,
+            highlight_color: Color::Red,
+            cr_mode: CrMode::Strip,
+            wrap_mode: WrapMode::Char,
+            tab_width: DEFAULT_TAB_WIDTH,
+            last_wrap_mode: WrapMode::Char,
+            max_rows: None,
+            wide_gap_marker: None,
+            wrap_marker: None,
+            keep_indent: false,
+            squeeze_blank_lines: false,
+            query_mode: false,
+            command_mode: false,
+            filter_mode: false,
+            label_mode: false,
+            overlay: None,
+            show_scrollbar: false,
+            line_numbers: LineNumbers::Hidden,
+            command: LineEdit::new(),
+            filter_input: LineEdit::new(),
+            filter: None,
+            filter_context: 0,
+            separator_rows: vec![],
+            row_indents: vec![],
+            query: LineEdit::new(),
+            search_history: vec![],
+            history_pos: None,
+            loading: None,
+            message: RefCell::new(None),
+            needs_update: Cell::new(true),
+            show_status_line: true,
+            segment_cache: RefCell::new(SegmentCache::default()),
+            match_positions: RefCell::new(None),
+            query_regex: RefCell::new(None),
+        };
+        scr.recalc_lines();
+        scr.fix_current_top();
+
+        scr
+    }
+
+    pub fn resized(&mut self) {
+        if let Some((width, height)) = term_size::dimensions_stdout() {
+            self.update_size(width, height)
+        }
+    }
+
+    pub fn update_size(&mut self, width: usize, height: usize) {
+        let (width, height) = (width.max(1), height.max(1));
+        if self.width == width && self.screen_height == height {
+            return;
+        }
+
+        // Wrapping doesn't depend on the height.
+        if self.width != width {
+            self.width = width;
+            self.recalc_lines();
+        }
+        self.screen_height = height;
+        self.layout_panes();
+    }
+
+    /// Splits the screen into two panes showing the same contents, or closes the pane not focused.
+    /// The new pane below is focused and starts where the screen was, so the upper one keeps
+    /// showing it while scrolling on.
+    pub fn toggle_split(&mut self) {
+        if self.split.take().is_none() {
+            if self.screen_height < 4 {
+                return self.show_message("the terminal is too small to split");
+            }
+            self.split = Some(Split {
+                top_line: self.top_line_number() - 1,
+                focus_upper: false,
+            });
+        }
+        self.layout_panes();
+    }
+
+    /// Moves the focus to the other pane while the screen is split.
+    pub fn switch_pane(&mut self) {
+        let top_line = self.top_line_number() - 1;
+        let split = match &mut self.split {
+            Some(split) => split,
+            None => return self.show_message("the screen is not split"),
+        };
+        let other_line = replace(&mut split.top_line, top_line);
+        split.focus_upper = !split.focus_upper;
+        self.layout_panes();
+        self.scroll_to_source_line(other_line);
+    }
+
+    /// Sets the height and the position of the focused pane: the upper half of the terminal or
+    /// the rest of it while split, or the whole terminal.
+    fn layout_panes(&mut self) {
+        let upper_height = self.screen_height / 2;
+        let (top_row, height) = match &self.split {
+            Some(split) if split.focus_upper => (0, upper_height),
+            Some(_) => (upper_height, self.screen_height - upper_height),
+            None => (0, self.screen_height),
+        };
+        self.top_row = top_row;
+        self.height = height.max(1);
+        self.fix_current_top();
+    }
+
+    /// Appends newly read input to the contents.
+    pub fn append(&mut self, chunk: &str) {
+        if self.showing_hex_dump {
+            self.append_to_hex_dump(chunk);
+        } else {
+            // The last source line may be continued by `chunk`, so it is wrapped again.
+            if self.wrapped_all {
+                self.unwrap_from(self.last_line_start, self.last_source_line);
+            }
+            self.contents.push_str(chunk);
+        }
+        // This wraps the new lines if they are on the screen. The sticky header may grow, too.
+        self.fix_current_top();
+        if let Some((top_line, read_lines)) = &mut self.top_to_restore {
+            // Scroll back once a screen below the line has been read, so that it can be at the
+            // top.
+            *read_lines += chunk.matches('\n').count();
+            if *read_lines > *top_line + self.height {
+                self.restore_top();
+            }
+        }
+        if self.following {
+            self.down_by(MoveUnit::Entire, 1);
+        }
+    }
+
+    /// Appends `chunk` to the text while its hex dump is shown, and dumps the new bytes. The last
+    /// line of the dump is made again if it had less than `BYTES_PER_LINE` bytes.
+    fn append_to_hex_dump(&mut self, chunk: &str) {
+        let text = self.hidden_text.get_or_insert_with(String::new);
+        let dumped_len = text.len() / BYTES_PER_LINE * BYTES_PER_LINE;
+        let line_start = if dumped_len < text.len() {
+            let without_newline = &self.contents[..self.contents.len() - 1];
+            without_newline.rfind('\n').map_or(0, |pos| pos + 1)
+        } else {
+            self.contents.len()
+        };
+        text.push_str(chunk);
+        let dump = hex_dump_at(&text.as_bytes()[dumped_len..], dumped_len);
+
+        let source_line = dumped_len / BYTES_PER_LINE;
+        if self.wrapped_all || self.last_line_start > line_start {
+            self.unwrap_from(line_start, source_line);
+        }
+        self.contents.truncate(line_start);
+        self.contents.push_str(&dump);
+    }
+
+    /// Throws away the wrapped lines of the source line `source_line` starting at the byte
+    /// `line_start` and after, so that they are wrapped again from there.
+    fn unwrap_from(&mut self, line_start: usize, source_line: usize) {
+        let kept = self
+            .source_lines
+            .partition_point(|&wrapped| wrapped < source_line);
+        self.lines.truncate(kept);
+        self.source_lines.truncate(kept);
+        self.row_indents.truncate(kept);
+        self.separator_rows.retain(|&row| row < kept);
+        self.last_line_start = line_start;
+        self.last_source_line = source_line;
+        self.wrapped_all = false;
+        self.segment_cache.get_mut().segments.clear();
+        *self.match_positions.get_mut() = None;
+    }
+
+    /// Throws away the contents to read them again from the beginning, e.g. the output of a
+    /// command run again. The line at the top of the screen comes back there once it is read.
+    pub fn restart_contents(&mut self) {
+        self.top_to_restore = Some((self.top_line_number() - 1, 0));
+        self.contents.clear();
+        if self.showing_hex_dump {
+            self.hidden_text = Some(String::new());
+        }
+        self.recalc_lines();
+        self.current_top = 0;
+        self.fix_current_top();
+    }
+
+    /// Scrolls back to the line which was at the top before `restart_contents()`.
+    fn restore_top(&mut self) {
+        if let Some((top_line, _)) = self.top_to_restore.take() {
+            self.scroll_to_source_line(top_line);
+        }
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.following
+    }
+
+    /// Starts or stops following the input, like `tail -f`: the screen stays at the end and shows
+    /// new input as it arrives.
+    pub fn set_following(&mut self, following: bool) {
+        if following && self.showing_hex_dump {
+            self.show_message("cannot follow the hex dump");
+            return;
+        }
+
+        self.following = following;
+        self.needs_update.set(true);
+        if following {
+            self.down_by(MoveUnit::Entire, 1);
+        }
+    }
+
+    /// Appends what has been written to the file since it was read. If the file has shrunk, e.g.
+    /// it was truncated by log rotation, it is read again from the beginning.
+    pub fn read_file_growth(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        if self.formatter.is_some() {
+            return self.reload_if_changed();
+        }
+
+        let read_growth = |file_len| -> io::Result<Option<Vec<u8>>> {
+            let mut file = File::open(path)?;
+            if file.metadata()?.len() < file_len {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(file_len))?;
+            let mut growth = vec![];
+            file.read_to_end(&mut growth)?;
+            Ok(Some(growth))
+        };
+        match read_growth(self.file_len) {
+            Ok(Some(growth)) => {
+                // A character being written may be cut in the middle; it is read next time. Only
+                // UTF-8 is cared about.
+                let complete_len = match std::str::from_utf8(&growth) {
+                    Err(e) if e.error_len().is_none() && self.encoding.is_none() => e.valid_up_to(),
+                    _ => growth.len(),
+                };
+                if complete_len > 0 {
+                    self.file_len += complete_len as u64;
+                    self.file_modified = modified_time(path);
+                    let text = self.decode(&growth[..complete_len]);
+                    self.append(&text);
+                }
+            }
+            Ok(None) => self.reload(),
+            Err(e) => self.show_message(format!("failed to read the file: {}", e)),
+        }
+    }
+
+    /// Reads the file again if it has been modified or its size has changed since it was read.
+    pub fn reload_if_changed(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let changed = match metadata(path) {
+            Ok(metadata) => {
+                metadata.len() != self.file_len || metadata.modified().ok() != self.file_modified
+            }
+            // The file may be being replaced; it is read once it is back.
+            Err(_) => false,
+        };
+        if changed {
+            self.reload();
+        }
+    }
+
+    /// Reads the file again, e.g. after it was rewritten. The same source line is kept at the top
+    /// unless the file has shrunk below it.
+    pub fn reload(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                self.show_message("cannot reload the standard input");
+                return;
+            }
+        };
+
+        let modified = modified_time(path);
+        match read(path) {
+            Ok(bytes) => {
+                let top_line = self.top_line_number() - 1;
+                self.file_len = bytes.len() as u64;
+                self.file_modified = modified;
+                let mut text = self.decode(&bytes);
+                if let Some(formatter) = &self.formatter {
+                    text = formatter(&text);
+                }
+                if self.showing_hex_dump {
+                    self.contents = hex_dump(&bytes);
+                    self.hidden_text = Some(text);
+                } else {
+                    self.contents = text;
+                }
+                self.recalc_lines();
+                self.scroll_to_source_line(top_line);
+                if self.following {
+                    self.down_by(MoveUnit::Entire, 1);
+                }
+                self.show_message("reloaded");
+            }
+            Err(e) => self.show_message(format!("failed to reload: {}", e)),
+        }
+    }
+
+    /// Shows a spinner in the status line while more input is expected.
+    pub fn set_loading(&mut self, loading: bool) {
+        if loading {
+            self.loading = Some(0);
+        } else if self.loading.take().is_some() {
+            // The contents may have become shorter than where they were read again for.
+            self.restore_top();
+            self.show_message("finished loading");
+        }
+        self.needs_update.set(true);
+    }
+
+    /// Advances the loading spinner.
+    pub fn tick_loading(&mut self) {
+        if let Some(frame) = &mut self.loading {
+            *frame = frame.wrapping_add(1);
+            self.needs_update.set(true);
+        }
+    }
+
+    /// Records the file the contents were read from. It is assumed that the contents are the whole
+    /// file as it is now, which is where following the file continues.
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.file_len = metadata(&path).map_or(0, |metadata| metadata.len());
+        self.file_modified = modified_time(&path);
+        self.path = Some(path);
+    }
+
+    /// Switches between the text of the file and its hex dump, keeping about the same part of the
+    /// file on the screen.
+    pub fn toggle_hex_dump(&mut self) {
+        let offset = self.top_offset();
+        if let Some(text) = self.hidden_text.take() {
+            self.contents = text;
+        } else {
+            // The file is read again for the bytes which the text doesn't keep. Other input, e.g.
+            // the standard input, is only kept as text, so its bytes are those of the text.
+            let bytes = match &self.path {
+                Some(path) => match read(path) {
+                    Ok(bytes) => Cow::Owned(bytes),
+                    Err(e) => {
+                        self.show_message(format!("failed to read the file: {}", e));
+                        return;
+                    }
+                },
+                None => Cow::Borrowed(self.contents.as_bytes()),
+            };
+            let dump = hex_dump(&bytes);
+            self.hidden_text = Some(replace(&mut self.contents, dump));
+        }
+
+        // The text is converted from the bytes lossily, but the offsets are close enough.
+        self.showing_hex_dump = !self.showing_hex_dump;
+        self.following = false;
+        self.recalc_lines();
+        let source_line = if self.showing_hex_dump {
+            offset / BYTES_PER_LINE
+        } else {
+            source_line_at(&self.contents, offset, self.cr_mode).0
+        };
+        self.scroll_to_source_line(source_line);
+    }
+
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Reads the file as text in `encoding`, a name like `Shift_JIS`, when it is read again.
+    pub fn set_encoding(&mut self, encoding: Option<&'static str>) {
+        self.encoding = encoding;
+    }
+
+    /// Sets how the text of the file is formatted when it is read again, the way the contents
+    /// were formatted. Formatting needs the whole file, so following it reads it all again.
+    pub fn set_formatter(&mut self, formatter: impl Fn(&str) -> String + 'static) {
+        self.formatter = Some(Box::new(formatter));
+    }
+
+    /// Converts `bytes` of the file to text in its encoding.
+    fn decode(&self, bytes: &[u8]) -> String {
+        self.encoding
+            .and_then(|encoding| decode(bytes, Some(encoding)).ok().flatten())
+            .map_or_else(
+                || String::from_utf8_lossy(bytes).into_owned(),
+                |(text, _)| text,
+            )
+    }
+
+    /// Returns the byte offset in the input where the line at the top of the screen starts.
+    fn top_offset(&self) -> usize {
+        let source_line = self.top_line_number() - 1;
+        if self.showing_hex_dump {
+            return source_line * BYTES_PER_LINE;
+        }
+
+        source_line_texts(&self.contents, self.cr_mode)
+            .nth(source_line)
+            .map_or(0, |text| {
+                text.as_ptr() as usize - self.contents.as_ptr() as usize
+            })
+    }
+
+    /// Returns the 1-based source line number of the line shown at the top of the screen.
+    pub fn top_line_number(&self) -> usize {
+        self.source_lines
+            .get(self.current_top as usize)
+            .map_or(1, |&line| line + 1)
+    }
+
+    pub fn show_message(&self, message: impl Into<String>) {
+        *self.message.borrow_mut() = Some(message.into());
+        self.needs_update.set(true);
+    }
+
+    /// Returns whether a message is shown in the prompt, until the next key is typed.
+    pub fn has_message(&self) -> bool {
+        self.message.borrow().is_some()
+    }
+
+    /// Forces the next `draw()` to repaint the whole screen, e.g. after another program used the
+    /// terminal.
+    pub fn redraw(&self) {
+        self.needs_update.set(true);
+    }
+
+    /// Pins the first `lines` source lines at the top of the screen, e.g. the header of a table.
+    pub fn set_sticky_header(&mut self, lines: usize) {
+        self.sticky_header = lines;
+        self.fix_current_top();
+    }
+
+    /// Sets how many lines of context are shown above a search match jumped to.
+    pub fn set_scrolloff(&mut self, scrolloff: usize) {
+        self.scrolloff = scrolloff;
+    }
+
+    /// Sets whether searching continues from the other end of the contents when there are no more
+    /// matches.
+    pub fn set_wrap_search(&mut self, wrap_search: bool) {
+        self.wrap_search = wrap_search;
+    }
+
+    pub fn set_case_mode(&mut self, case_mode: CaseMode) {
+        self.case_mode = case_mode;
+        // Everything found or highlighted for the query is stale now.
+        *self.query_regex.borrow_mut() = None;
+        *self.match_positions.borrow_mut() = None;
+        self.segment_cache.borrow_mut().segments.clear();
+        self.needs_update.set(true);
+    }
+
+    /// Switches to the next of the case-sensitive, smart-case and case-insensitive search, and
+    /// tells which one it is.
+    pub fn toggle_case_mode(&mut self) {
+        let (case_mode, message) = match self.case_mode {
+            CaseMode::Sensitive => (CaseMode::Smart, "smart-case search"),
+            CaseMode::Smart => (CaseMode::Insensitive, "case-insensitive search"),
+            CaseMode::Insensitive => (CaseMode::Sensitive, "case-sensitive search"),
+        };
+        self.set_case_mode(case_mode);
+        self.show_message(message);
+    }
+
+    /// Sets how many lines are kept visible from the previous view when scrolling by a page. It is
+    /// limited to 1 or 2 lines.
+    pub fn set_page_overlap(&mut self, overlap: usize) {
+        self.page_overlap = overlap.clamp(1, 2);
+    }
+
+    /// Sets how many lines `unit` scrolls by. `None` or zero restores the default: a line, half the
+    /// screen and the screen minus the overlap.
+    pub fn set_scroll_amount(&mut self, unit: MoveUnit, amount: Option<usize>) {
+        let amount = amount.filter(|&amount| amount > 0);
+        match unit {
+            MoveUnit::Line => self.line_amount = amount,
+            MoveUnit::HalfPage => self.half_page_amount = amount,
+            MoveUnit::Page => self.page_amount = amount,
+            MoveUnit::Entire => {}
+        }
+    }
+
+    /// Sets the color of the search matches.
+    pub fn set_highlight_color(&mut self, color: Color) {
+        self.highlight_color = color;
+        self.segment_cache.get_mut().segments.clear();
+        self.needs_update.set(true);
+    }
+
+    pub fn is_scrollbar_shown(&self) -> bool {
+        self.show_scrollbar
+    }
+
+    /// Shows or hides the scrollbar. The lines are wrapped again since it takes a column.
+    pub fn toggle_scrollbar(&mut self) {
+        self.show_scrollbar = !self.show_scrollbar;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Returns whether the scrollbar is shown in the screen column `column`.
+    pub fn is_scrollbar_column(&self, column: usize) -> bool {
+        self.show_scrollbar && column + 1 == self.width
+    }
+
+    pub fn set_line_numbers(&mut self, line_numbers: LineNumbers) {
+        if self.line_numbers == line_numbers {
+            return;
+        }
+
+        // The width of the lines changes only when the gutter appears or disappears, but
+        // rewrapping is cheap enough.
+        self.line_numbers = line_numbers;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Switches to the next of no line numbers, the absolute ones and the relative ones.
+    pub fn toggle_line_numbers(&mut self) {
+        self.set_line_numbers(match self.line_numbers {
+            LineNumbers::Hidden => LineNumbers::Absolute,
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Hidden,
+        });
+    }
+
+    /// Scrolls so that the thumb of the scrollbar starts at the screen row `row`, e.g. where it is
+    /// clicked or dragged to.
+    pub fn scroll_to_scrollbar_row(&mut self, row: usize) {
+        let body_height = self.body_height().max(1);
+        let row = row.saturating_sub(self.top_row + self.header_rows());
+        let row = min(row, body_height);
+        let scrollable_rows = self.estimated_rows().saturating_sub(self.header_rows());
+        let top = self.header_rows() + row * scrollable_rows / body_height;
+        self.wrap_until(top + self.contents_height());
+        self.current_top = top as isize;
+        self.fix_current_top();
+    }
+
+    pub fn toggle_status_line(&mut self) {
+        self.show_status_line = !self.show_status_line;
+        self.fix_current_top();
+    }
+
+    /// Toggles marking trailing whitespace with `·` and showing control characters in caret
+    /// notation such as `^M`.
+    pub fn toggle_nonprinting(&mut self) {
+        self.show_nonprinting = !self.show_nonprinting;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    pub fn toggle_whitespace(&mut self) {
+        self.show_whitespace = !self.show_whitespace;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Prints control characters as they are, like `less -r`, instead of in the caret notation.
+    pub fn set_raw_control_chars(&mut self, raw_control_chars: bool) {
+        if self.raw_control_chars == raw_control_chars {
+            return;
+        }
+
+        self.raw_control_chars = raw_control_chars;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    pub fn set_cr_mode(&mut self, cr_mode: CrMode) {
+        if self.cr_mode == cr_mode {
+            return;
+        }
+
+        self.cr_mode = cr_mode;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    pub fn set_wrap_mode(&mut self, wrap_mode: WrapMode) {
+        if self.wrap_mode == wrap_mode {
+            return;
+        }
+
+        self.wrap_mode = wrap_mode;
+        self.left_column = 0;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Switches between chopping the long lines and wrapping them as before, keeping the line at
+    /// the top of the screen.
+    pub fn toggle_wrap(&mut self) {
+        let source_line = self.top_line_number() - 1;
+        if self.wrap_mode == WrapMode::None {
+            self.set_wrap_mode(self.last_wrap_mode);
+        } else {
+            self.last_wrap_mode = self.wrap_mode;
+            self.set_wrap_mode(WrapMode::None);
+        }
+        self.scroll_to_source_line(source_line);
+    }
+
+    /// Puts the tab stops every `tab_width` columns.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        let tab_width = tab_width.max(1);
+        if self.tab_width == tab_width {
+            return;
+        }
+
+        self.tab_width = tab_width;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Limits how many screen lines a single source line takes, so a huge line doesn't flood the
+    /// screen. The rest of such a line is replaced with `…`.
+    pub fn set_max_rows(&mut self, max_rows: Option<usize>) {
+        let max_rows = max_rows.map(|n| n.max(1));
+        if self.max_rows == max_rows {
+            return;
+        }
+
+        self.max_rows = max_rows;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Fills the column left empty at the right edge when a wide character doesn't fit, so that
+    /// the right edges of wrapped lines line up.
+    pub fn set_wide_gap_marker(&mut self, marker: Option<char>) {
+        self.wide_gap_marker = marker;
+        self.segment_cache.get_mut().segments.clear();
+        self.needs_update.set(true);
+    }
+
+    /// Marks the continued rows of wrapped lines with `marker` at their beginning.
+    pub fn set_wrap_marker(&mut self, marker: Option<String>) {
+        if self.wrap_marker == marker {
+            return;
+        }
+
+        self.wrap_marker = marker;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    /// Indents the continued rows of wrapped lines as much as the beginning of the line.
+    pub fn set_keep_indent(&mut self, keep_indent: bool) {
+        if self.keep_indent == keep_indent {
+            return;
+        }
+
+        self.keep_indent = keep_indent;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    pub fn set_squeeze_blank_lines(&mut self, squeeze_blank_lines: bool) {
+        if self.squeeze_blank_lines == squeeze_blank_lines {
+            return;
+        }
+
+        self.squeeze_blank_lines = squeeze_blank_lines;
+        self.recalc_lines();
+        self.fix_current_top();
+    }
+
+    pub fn get_contents(&self) -> &str {
+        &self.contents
+    }
+
+    /// Returns the lines currently shown on the screen, including the sticky header.
+    pub fn visible_lines(&self) -> Vec<&str> {
+        (0..self.header_rows())
+            .chain(self.body_range())
+            .map(|idx| self.lines[idx].as_str())
+            .collect()
+    }
+
+    /// Returns whether all the lines fit in the screen at once.
+    pub fn fits_in_screen(&self) -> bool {
+        self.lines.len() <= self.contents_height()
+    }
+
+    /// Returns whether the last line is visible.
+    pub fn is_at_end(&self) -> bool {
+        self.current_top as usize >= self.max_top()
+    }
+
+    pub fn get_query(&self) -> &str {
+        self.query.as_str()
+    }
+
+    /// Returns the regular expression to search for the query with, or `None` if the query is
+    /// empty. A query which isn't a valid regular expression, e.g. an unclosed `(` while it is
+    /// being typed, is searched for as it is.
+    fn query_regex(&self) -> Option<Regex> {
+        let query = self.get_query();
+        if query.is_empty() {
+            return None;
+        }
+
+        let mut cache = self.query_regex.borrow_mut();
+        if cache.as_ref().is_none_or(|(cached, _)| cached != query) {
+            *cache = Some((query.to_string(), self.compile_pattern(query)?));
+        }
+
+        cache.as_ref().map(|(_, regex)| regex.clone())
+    }
+
+    /// Compiles `pattern` typed for searching or filtering into a regular expression, ignoring
+    /// the case as the case mode says. An invalid regular expression matches itself literally.
+    fn compile_pattern(&self, pattern: &str) -> Option<Regex> {
+        let ignore_case = match self.case_mode {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !pattern.chars().any(char::is_uppercase),
+        };
+        let build = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+        };
+        build(pattern)
+            .or_else(|_| build(&regex::escape(pattern)))
+            .ok()
+    }
+
+    /// Returns the query for editing in the prompt.
+    pub fn get_query_mut(&mut self) -> &mut LineEdit {
+        self.needs_update.set(true);
+        &mut self.query
+    }
+
+    pub fn is_query_mode(&self) -> bool {
+        self.query_mode
+    }
+
+    pub fn set_query_mode(&mut self, mode: bool) {
+        self.needs_update.set(true);
+        self.query_mode = mode;
+        self.history_pos = None;
+        self.query_origin = if mode {
+            Some((self.current_top, self.last_match.clone()))
+        } else {
+            None
+        };
+    }
+
+    /// Scrolls to the first match of the query being typed at or after the top line when the
+    /// query started to be typed. If there is none, the screen goes back there.
+    pub fn search_incrementally(&mut self) {
+        self.restore_query_origin();
+        let top = match &self.query_origin {
+            Some((top, _)) => *top,
+            None => return,
+        };
+        let regex = match self.query_regex() {
+            Some(regex) => regex,
+            None => return,
+        };
+
+        self.wrap_until(usize::MAX);
+        let found = match self.find_match_after(&regex, max(top, 0) as usize, None) {
+            None if self.wrap_search => self.find_match_after(&regex, 0, None),
+            found => found,
+        };
+        if let Some((line, range)) = found {
+            self.jump_to(line, range);
+        }
+    }
+
+    /// Goes back to where the screen was when the query started to be typed.
+    pub fn restore_query_origin(&mut self) {
+        if let Some((top, last_match)) = self.query_origin.clone() {
+            self.current_top = top;
+            self.last_match = last_match;
+            self.fix_current_top();
+        }
+    }
+
+    pub fn search_history(&self) -> &[String] {
+        &self.search_history
+    }
+
+    pub fn set_search_history(&mut self, history: Vec<String>) {
+        self.search_history = history;
+        self.history_pos = None;
+    }
+
+    /// Adds the current query to the search history, unless it is the same as the last one.
+    pub fn push_search_history(&mut self) {
+        let query = self.get_query();
+        if !query.is_empty() && self.search_history.last().map(String::as_str) != Some(query) {
+            self.search_history.push(query.to_string());
+        }
+        self.history_pos = None;
+    }
+
+    /// Replaces the query being typed with the previous entry in the search history.
+    pub fn prev_search_history(&mut self) {
+        let pos = match &self.history_pos {
+            Some((0, _)) => return,
+            Some((pos, _)) => pos - 1,
+            None => match self.search_history.len().checked_sub(1) {
+                Some(last) => last,
+                None => return,
+            },
+        };
+
+        let typed = match self.history_pos.take() {
+            Some((_, typed)) => typed,
+            None => self.query.as_str().to_string(),
+        };
+        self.history_pos = Some((pos, typed));
+        let entry = self.search_history[pos].clone();
+        self.get_query_mut().set(entry);
+    }
+
+    /// Replaces the query being typed with the next entry in the search history, or with what was
+    /// typed before browsing the history after the last entry.
+    pub fn next_search_history(&mut self) {
+        let entry = match self.history_pos.take() {
+            Some((pos, typed)) if pos + 1 < self.search_history.len() => {
+                let entry = self.search_history[pos + 1].clone();
+                self.history_pos = Some((pos + 1, typed));
+                entry
+            }
+            Some((_, typed)) => typed,
+            None => return,
+        };
+        self.get_query_mut().set(entry);
+    }
+
+    /// Shows a list of `items` in a box over the contents, with the item `selected` highlighted,
+    /// or hides it if `None`.
+    pub fn set_overlay(&mut self, overlay: Option<(Vec<String>, usize)>) {
+        self.overlay = overlay;
+        self.needs_update.set(true);
+    }
+
+    pub fn is_label_mode(&self) -> bool {
+        self.label_mode
+    }
+
+    /// Shows or hides a label at the beginning of each row on the screen. See `jump_to_label`.
+    pub fn set_label_mode(&mut self, mode: bool) {
+        self.needs_update.set(true);
+        self.label_mode = mode;
+        if mode {
+            self.show_message("type a label to jump to the line");
+        }
+    }
+
+    /// Scrolls the row labeled `label` to the top of the screen and leaves the label mode.
+    pub fn jump_to_label(&mut self, label: char) {
+        self.set_label_mode(false);
+        match ROW_LABELS.chars().position(|ch| ch == label) {
+            Some(pos) if pos < self.body_range().len() => {
+                self.current_top += pos as isize;
+                self.fix_current_top();
+            }
+            _ => self.show_message(format!("no line is labeled `{}`", label)),
+        }
+    }
+
+    /// Returns the command being typed at the `:` prompt for editing.
+    pub fn get_command_mut(&mut self) -> &mut LineEdit {
+        self.needs_update.set(true);
+        &mut self.command
+    }
+
+    pub fn is_command_mode(&self) -> bool {
+        self.command_mode
+    }
+
+    pub fn set_command_mode(&mut self, mode: bool) {
+        self.needs_update.set(true);
+        self.command_mode = mode;
+    }
+
+    /// Returns the pattern being typed at the `&` prompt for editing.
+    pub fn get_filter_input_mut(&mut self) -> &mut LineEdit {
+        self.needs_update.set(true);
+        &mut self.filter_input
+    }
+
+    pub fn is_filter_mode(&self) -> bool {
+        self.filter_mode
+    }
+
+    pub fn set_filter_mode(&mut self, mode: bool) {
+        self.needs_update.set(true);
+        self.filter_mode = mode;
+    }
+
+    /// Sets how many lines of context are shown before and after each line passing the filter set
+    /// after this.
+    pub fn set_filter_context(&mut self, context: usize) {
+        self.filter_context = context;
+    }
+
+    /// Shows only the source lines matching `pattern`, like `&` of `less`. A pattern starting with
+    /// `!` hides the lines matching the rest instead. An empty pattern shows all the lines again.
+    /// The line at the top of the screen stays there if it is still shown.
+    pub fn set_filter(&mut self, pattern: &str) {
+        let top_source_line = self.top_line_number().saturating_sub(1);
+        let (pattern, inverted) = match pattern.strip_prefix('!') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        self.filter = if pattern.is_empty() {
+            None
+        } else {
+            self.compile_pattern(pattern).map(|regex| Filter {
+                regex,
+                inverted,
+                context: self.filter_context,
+                last_passed: None,
+                last_shown: None,
+            })
+        };
+        self.last_match = None;
+        self.recalc_lines();
+        self.wrap_through_source_line(top_source_line);
+        self.current_top = self.source_lines.partition_point(|&l| l < top_source_line) as isize;
+        self.fix_current_top();
+
+        if self.filter.is_none() {
+            self.show_message("showing all the lines");
+        } else {
+            self.wrap_until(self.contents_height());
+            if self.lines.is_empty() && inverted {
+                self.show_message(format!("all the lines match `{}`", pattern));
+            } else if self.lines.is_empty() {
+                self.show_message(format!("no lines match `{}`", pattern));
+            }
+        }
+    }
+
+    /// Scrolls up by `count` times `unit`.
+    pub fn up_by(&mut self, unit: MoveUnit, count: usize) {
+        if let MoveUnit::Entire = unit {
+            self.note_jump();
+        }
+        self.scroll(-self.scroll_amount(unit, count));
+    }
+
+    /// Scrolls down by `count` times `unit`.
+    pub fn down_by(&mut self, unit: MoveUnit, count: usize) {
+        if let MoveUnit::Entire = unit {
+            self.note_jump();
+        }
+        self.scroll(self.scroll_amount(unit, count));
+    }
+
+    /// Marks the line at the top of the screen with the letter `mark`, like `m` of `less`.
+    pub fn set_mark(&mut self, mark: char) {
+        if !mark.is_ascii_alphabetic() {
+            self.show_message(format!("invalid mark `{}` (expected a letter)", mark));
+            return;
+        }
+
+        let line_number = self.top_line_number();
+        self.marks.insert(mark, line_number - 1);
+        self.show_message(format!("marked line {} as `{}`", line_number, mark));
+    }
+
+    /// Scrolls to the line marked with `mark`, or back to where the last jump started from if
+    /// `mark` is `'`.
+    pub fn jump_to_mark(&mut self, mark: char) {
+        let source_line = if mark == '\'' {
+            self.jump_origin
+        } else {
+            self.marks.get(&mark).copied()
+        };
+        match source_line {
+            Some(source_line) => self.goto_line(source_line + 1),
+            None if mark == '\'' => self.show_message("no previous position"),
+            None => self.show_message(format!("mark `{}` is not set", mark)),
+        }
+    }
+
+    /// Goes back to where the screen was before the last jump in the jump history, like `Ctrl-O`
+    /// of Vim.
+    pub fn jump_back(&mut self) {
+        if self.jump_pos == 0 {
+            self.show_message("no older position");
+            return;
+        }
+
+        // Keep the current position, to come back with `Ctrl-I`.
+        if self.jump_pos == self.jump_history.len() {
+            self.jump_history.push(self.top_line_number() - 1);
+        }
+        self.jump_pos -= 1;
+        self.scroll_to_source_line(self.jump_history[self.jump_pos]);
+    }
+
+    /// Goes forward to where `jump_back()` came from, like `Ctrl-I` of Vim.
+    pub fn jump_forward(&mut self) {
+        if self.jump_pos + 1 >= self.jump_history.len() {
+            self.show_message("no newer position");
+            return;
+        }
+
+        self.jump_pos += 1;
+        self.scroll_to_source_line(self.jump_history[self.jump_pos]);
+    }
+
+    /// Remembers the line at the top of the screen before jumping somewhere, for `''` and the jump
+    /// history. The positions gone back from in the history are forgotten, like in Vim.
+    fn note_jump(&mut self) {
+        let source_line = self.top_line_number() - 1;
+        self.jump_origin = Some(source_line);
+        self.jump_history.truncate(self.jump_pos);
+        if self.jump_history.last() != Some(&source_line) {
+            self.jump_history.push(source_line);
+        }
+        if self.jump_history.len() > JUMP_HISTORY_LEN {
+            self.jump_history.remove(0);
+        }
+        self.jump_pos = self.jump_history.len();
+    }
+
+    /// Starts a selection at the line shown at the top of the screen.
+    pub fn set_selection_start(&mut self) {
+        self.selection_start = Some(self.top_line_number() - 1);
+        self.selection_end = None;
+        self.show_message(format!(
+            "selection starts at line {}",
+            self.top_line_number()
+        ));
+    }
+
+    /// Ends the selection at the line shown at the top of the screen.
+    pub fn set_selection_end(&mut self) {
+        if self.selection_start.is_none() {
+            self.show_message("selection is not started");
+            return;
+        }
+
+        self.selection_end = Some(self.top_line_number() - 1);
+        let selection = self.selection().unwrap();
+        self.show_message(format!(
+            "selected lines {}-{}",
+            selection.start() + 1,
+            selection.end() + 1
+        ));
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection_start = None;
+        self.selection_end = None;
+        self.needs_update.set(true);
+    }
+
+    /// Returns the selected source lines, or `None` if nothing is selected.
+    pub fn selected_lines(&mut self) -> Option<Vec<String>> {
+        let selection = self.selection()?;
+        self.wrap_through_source_line(*selection.end());
+        let first = self
+            .source_lines
+            .partition_point(|&source_line| source_line < *selection.start());
+        let last = self
+            .source_lines
+            .partition_point(|&source_line| source_line <= *selection.end());
+
+        Some(self.join_rows(first..last))
+    }
+
+    /// Returns the text to copy: the selected lines, or the source lines on the screen if nothing
+    /// is selected. The wrapped lines are joined back and the escape sequences are left out.
+    pub fn text_to_copy(&mut self) -> String {
+        let lines = match self.selected_lines() {
+            Some(lines) => lines,
+            None => {
+                let mut lines = self.join_rows(0..self.header_rows());
+                lines.extend(self.join_rows(self.body_range()));
+                lines
+            }
+        };
+
+        lines
+            .iter()
+            .map(|line| shown_text(line).0)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Returns the source lines shown while filtering, or `None` without a filter.
+    pub fn filtered_lines(&mut self) -> Option<Vec<String>> {
+        self.filter.as_ref()?;
+        self.wrap_until(usize::MAX);
+        Some(self.join_rows(0..self.lines.len()))
+    }
+
+    /// Joins the wrapped lines in `rows` back into their source lines. The separators of the
+    /// filtered lines are left out.
+    fn join_rows(&self, rows: Range<usize>) -> Vec<String> {
+        let mut lines: Vec<String> = vec![];
+        let mut last_source_line = None;
+        for idx in rows {
+            if self.is_separator_row(idx) {
+                continue;
+            }
+
+            let (line, source_line) = (&self.lines[idx], self.source_lines[idx]);
+            match lines.last_mut() {
+                Some(last) if last_source_line == Some(source_line) => last.push_str(line),
+                _ => lines.push(line.clone()),
+            }
+            last_source_line = Some(source_line);
+        }
+
+        lines
+    }
+
+    /// Scrolls down to the `count`-th blank line which ends a paragraph, like `}` in vi. Goes to
+    /// the end if there are no more paragraphs.
+    pub fn next_paragraph(&mut self, count: usize) {
+        let is_blank = |line: &String| line.trim().is_empty();
+        let mut line = self.current_top.max(0) as usize;
+        for _ in 0..count {
+            loop {
+                line += 1;
+                if line >= self.lines.len() {
+                    self.wrap_until(line + self.contents_height());
+                }
+                match self.lines.get(line) {
+                    Some(curr) if is_blank(curr) && !is_blank(&self.lines[line - 1]) => break,
+                    Some(_) => {}
+                    None => {
+                        self.down_by(MoveUnit::Entire, 1);
+                        return;
+                    }
+                }
+            }
+        }
+
+        self.current_top = line as isize;
+        self.fix_current_top();
+    }
+
+    /// Scrolls up to the `count`-th blank line which starts a paragraph, like `{` in vi. Goes to
+    /// the beginning if there are no more paragraphs.
+    pub fn prev_paragraph(&mut self, count: usize) {
+        let is_blank = |line: &String| line.trim().is_empty();
+        let mut line = self.current_top.max(0) as usize;
+        for _ in 0..count {
+            loop {
+                if line == 0 {
+                    self.up_by(MoveUnit::Entire, 1);
+                    return;
+                }
+                line -= 1;
+                if is_blank(&self.lines[line]) && !is_blank(&self.lines[line + 1]) {
+                    break;
+                }
+            }
+        }
+
+        self.current_top = line as isize;
+        self.fix_current_top();
+    }
+
+    /// Scrolls right by `count` times half the screen width, when lines are not wrapped. Stops
+    /// where the longest line on the screen ends.
+    pub fn scroll_right(&mut self, count: usize) {
+        if self.wrap_mode != WrapMode::None {
+            self.show_message("lines are wrapped; press w (or use -S) to scroll horizontally");
+            return;
+        }
+
+        let longest = self
+            .visible_lines()
+            .iter()
+            .map(|line| str_width(line))
+            .max()
+            .unwrap_or(0);
+        let amount = (self.text_width() / 2).max(1).saturating_mul(count);
+        self.left_column = min(
+            self.left_column.saturating_add(amount),
+            max(longest.saturating_sub(1), self.left_column),
+        );
+        self.needs_update.set(true);
+    }
+
+    /// Scrolls left by `count` times half the screen width, when lines are not wrapped.
+    pub fn scroll_left(&mut self, count: usize) {
+        let amount = (self.text_width() / 2).max(1).saturating_mul(count);
+        self.left_column = self.left_column.saturating_sub(amount);
+        self.needs_update.set(true);
+    }
+
+    pub fn prev(&mut self) {
+        let regex = match self.query_regex() {
+            Some(regex) => regex,
+            None => {
+                self.show_message("search query is not set");
+                return;
+            }
+        };
+
+        let cursor = self.search_cursor();
+        // Even without wrapping around, the whole contents is wrapped to count all the matches.
+        self.wrap_until(usize::MAX);
+
+        // Without a match to continue from, the search starts from the line above the top line.
+        let found = match cursor {
+            Some((line, start)) => self.find_match_before(&regex, line, Some(start)),
+            None => self.find_match_before(&regex, max(self.current_top, 0) as usize, Some(0)),
+        };
+        if let Some((line, range)) = found {
+            self.jump_to(line, range);
+            return;
+        }
+
+        if self.wrap_search {
+            if let Some((line, range)) = self.find_match_before(&regex, self.lines.len(), None) {
+                let only_match = cursor == Some((line, range.start));
+                self.jump_to(line, range);
+                self.show_wrapped_message("bottom", only_match);
+                return;
+            }
+        }
+
+        self.show_message(format!("failed to find `{}`", self.get_query()));
+    }
+
+    pub fn next(&mut self) {
+        let regex = match self.query_regex() {
+            Some(regex) => regex,
+            None => {
+                self.show_message("search query is not set");
+                return;
+            }
+        };
+
+        let cursor = self.search_cursor();
+        self.wrap_until(usize::MAX);
+
+        // Without a match to continue from, the search starts from the top line.
+        let found = match cursor {
+            Some((line, start)) => self.find_match_after(&regex, line, Some(start)),
+            None => self.find_match_after(&regex, max(self.current_top, 0) as usize, None),
+        };
+        if let Some((line, range)) = found {
+            self.jump_to(line, range);
+            return;
+        }
+
+        if self.wrap_search {
+            if let Some((line, range)) = self.find_match_after(&regex, 0, None) {
+                let only_match = cursor == Some((line, range.start));
+                self.jump_to(line, range);
+                self.show_wrapped_message("top", only_match);
+                return;
+            }
+        }
+
+        self.show_message(format!("failed to find `{}`", self.get_query()));
+    }
+
+    /// Shows how many times the query appears on the screen, without scrolling.
+    pub fn count_visible_matches(&self) {
+        if self.get_query().is_empty() {
+            self.show_message("search query is not set");
+            return;
+        }
+
+        let count: usize = self
+            .visible_lines()
+            .iter()
+            .map(|line| {
+                match_ranges(line, self.query_regex().as_ref(), !self.show_nonprinting).len()
+            })
+            .sum();
+        match count {
+            0 => self.show_message("no matches on screen"),
+            1 => self.show_message("1 match on screen"),
+            _ => self.show_message(format!("{} matches on screen", count)),
+        }
+    }
+
+    /// Jumps to the first match in the whole contents, including the first line.
+    pub fn first(&mut self) {
+        let regex = match self.query_regex() {
+            Some(regex) => regex,
+            None => {
+                self.show_message("search query is not set");
+                return;
+            }
+        };
+
+        self.wrap_until(usize::MAX);
+        match self.find_match_after(&regex, 0, None) {
+            Some((line, range)) => self.jump_to(line, range),
+            None => self.show_message(format!("failed to find `{}`", self.get_query())),
+        }
+    }
+
+    /// Scrolls to the line with the bracket matching the first bracket on the top line, like `%` of
+    /// Vim. Brackets matched within the top line itself, e.g. `()` in `fn main() {`, are skipped.
+    /// Brackets in strings or comments are counted, too.
+    pub fn goto_matching_bracket(&mut self) {
+        const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+        let top = self.current_top as usize;
+        let found = self.lines.get(top).and_then(|line| {
+            // The first closing bracket without its opening one, or the first opening bracket left
+            // open at the end of the line.
+            let mut opens = [vec![], vec![], vec![]];
+            let mut first_close = None;
+            for (pos, ch) in line.char_indices() {
+                for (kind, &(open, close)) in PAIRS.iter().enumerate() {
+                    if ch == open {
+                        opens[kind].push(pos);
+                    } else if ch == close && opens[kind].pop().is_none() && first_close.is_none() {
+                        first_close = Some((pos, open, close, false));
+                    }
+                }
+            }
+            let first_open = PAIRS
+                .iter()
+                .zip(&opens)
+                .filter_map(|(&(open, close), opens)| Some((*opens.first()?, open, close, true)))
+                .min();
+            first_close.into_iter().chain(first_open).min()
+        });
+        let (pos, open, close, forward) = match found {
+            Some(found) => found,
+            None => {
+                self.show_message("no unmatched bracket on the top line");
+                return;
+            }
+        };
+
+        // Scanning from the bracket, the bracket itself opens a level and its counterpart closes
+        // one. The match is where the level drops back to zero.
+        let (bracket, counterpart) = if forward {
+            (open, close)
+        } else {
+            (close, open)
+        };
+        let mut depth = 0usize;
+        let mut step = |ch: char| {
+            if ch == bracket {
+                depth += 1;
+            } else if ch == counterpart {
+                depth -= 1;
+            }
+            depth == 0
+        };
+        let line = if forward {
+            self.wrap_until(usize::MAX);
+            let (first, rest) = self.lines[top..].split_first().unwrap();
+            if first[pos..].chars().any(&mut step) {
+                Some(top)
+            } else {
+                rest.iter()
+                    .position(|line| line.chars().any(&mut step))
+                    .map(|idx| top + 1 + idx)
+            }
+        } else {
+            let line = &self.lines[top];
+            let closes = line[..pos + close.len_utf8()].chars().rev().any(&mut step);
+            if closes {
+                Some(top)
+            } else {
+                self.lines[..top]
+                    .iter()
+                    .rposition(|line| line.chars().rev().any(&mut step))
+            }
+        };
+
+        match line {
+            Some(line) => {
+                self.note_jump();
+                self.current_top = line as isize;
+                self.fix_current_top();
+            }
+            None => self.show_message(format!("no bracket matching `{}`", bracket)),
+        }
+    }
+
+    /// Scrolls so that the screen line `percent`% of the way through the contents is at the top of
+    /// the screen, like `50%` of `less`.
+    pub fn goto_percent(&mut self, percent: usize) {
+        self.note_jump();
+        self.wrap_until(usize::MAX);
+        self.current_top = (self.lines.len() * min(percent, 100) / 100) as isize;
+        self.fix_current_top();
+    }
+
+    /// Scrolls so that the screen line containing the byte `offset` of the contents is at the top
+    /// of the screen. An offset beyond the end goes to the end.
+    pub fn goto_offset(&mut self, offset: usize) {
+        self.note_jump();
+        let offset = min(offset, self.contents.len());
+        let (source_line, line_start) = source_line_at(&self.contents, offset, self.cr_mode);
+        self.wrap_through_source_line(source_line);
+
+        // Find the screen line within the source line, counting the bytes of each. This is off by
+        // the bytes dropped in wrapping, e.g. carriage returns, which are few.
+        let mut row = self.source_lines.partition_point(|&l| l < source_line);
+        let mut column = offset - line_start;
+        while row + 1 < self.lines.len()
+            && self.source_lines[row + 1] == source_line
+            && column >= self.lines[row].len()
+        {
+            column -= self.lines[row].len();
+            row += 1;
+        }
+
+        self.current_top = row as isize;
+        self.fix_current_top();
+    }
+
+    /// Scrolls so that the current line, i.e. the match last jumped to or otherwise the line at
+    /// the top, is in the middle of the screen, like `zz` of Vim.
+    pub fn center(&mut self) {
+        self.place_current_line(self.body_height().saturating_sub(1) / 2);
+    }
+
+    /// Scrolls so that the current line is at the top of the screen, like `zt` of Vim.
+    pub fn current_line_to_top(&mut self) {
+        self.place_current_line(0);
+    }
+
+    /// Scrolls so that the current line is at the bottom of the screen, like `zb` of Vim.
+    pub fn current_line_to_bottom(&mut self) {
+        self.place_current_line(self.body_height().saturating_sub(1));
+    }
+
+    /// Scrolls so that the current line is `offset` rows below the top of the screen. The next
+    /// search still continues from the match last jumped to.
+    fn place_current_line(&mut self, offset: usize) {
+        let line = self.search_origin();
+        let is_at_match = self.search_cursor().is_some();
+        self.current_top = line as isize - offset as isize;
+        self.fix_current_top();
+        if is_at_match {
+            if let Some(last_match) = &mut self.last_match {
+                last_match.top = self.current_top;
+            }
+        }
+    }
+
+    /// Scrolls so that the (1-based) source line `line_number` is at the top of the screen.
+    pub fn goto_line(&mut self, line_number: usize) {
+        self.note_jump();
+        self.scroll_to_source_line(line_number.saturating_sub(1));
+    }
+
+    /// Scrolls so that the (0-based) source line `source_line` is at the top of the screen.
+    fn scroll_to_source_line(&mut self, source_line: usize) {
+        self.wrap_through_source_line(source_line);
+        self.current_top = self.source_lines.partition_point(|&l| l < source_line) as isize;
+        self.fix_current_top();
+    }
+
+    /// Finds the first match in the line `line` starting after the byte `after` (anywhere in the
+    /// line if `None`), or else in the lines below it.
+    fn find_match_after(
+        &self,
+        regex: &Regex,
+        line: usize,
+        after: Option<usize>,
+    ) -> Option<(usize, Range<usize>)> {
+        let in_line = self.lines.get(line).and_then(|text| {
+            self.find_matches(text, regex)
+                .into_iter()
+                .find(|range| after.is_none_or(|after| range.start > after))
+        });
+        if let Some(range) = in_line {
+            return Some((line, range));
+        }
+
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(line.saturating_add(1))
+            .find_map(|(line, text)| Some((line, self.find_matches(text, regex).first()?.clone())))
+    }
+
+    /// Finds the last match in the line `line` starting before the byte `before` (anywhere in the
+    /// line if `None`), or else in the lines above it.
+    fn find_match_before(
+        &self,
+        regex: &Regex,
+        line: usize,
+        before: Option<usize>,
+    ) -> Option<(usize, Range<usize>)> {
+        let in_line = self.lines.get(line).and_then(|text| {
+            self.find_matches(text, regex)
+                .into_iter()
+                .rev()
+                .find(|range| before.is_none_or(|before| range.start < before))
+        });
+        if let Some(range) = in_line {
+            return Some((line, range));
+        }
+
+        self.lines[..min(line, self.lines.len())]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(line, text)| Some((line, self.find_matches(text, regex).pop()?)))
+    }
+
+    /// Finds the matches of `regex` in the row `line` as it is shown, i.e. through the colors and
+    /// the overstruck characters unless the escape sequences are shown as they are.
+    fn find_matches(&self, line: &str, regex: &Regex) -> Vec<Range<usize>> {
+        find_matches(line, regex, !self.show_nonprinting)
+    }
+
+    /// Tells that the search continued from the `end` of the contents. `only_match` is whether it
+    /// came back to the match it started from.
+    fn show_wrapped_message(&self, end: &str, only_match: bool) {
+        if only_match {
+            self.show_message(format!("search wrapped to {}; this is the only match", end));
+        } else {
+            self.show_message(format!("search wrapped to {}", end));
+        }
+    }
+
+    /// Draws the screen to `out` if anything has changed since the last call. `out` is usually
+    /// the locked stdout, but any writer works, e.g. a `Vec<u8>` to inspect the output.
+    pub fn draw(&self, out: &mut impl Write) -> io::Result<()> {
+        if !self.needs_update.get() {
+            return Ok(());
+        }
+
+        let rows: Vec<_> = (0..self.header_rows()).chain(self.body_range()).collect();
+
+        // Segments of the lines which stay on the screen are reused. The others are dropped so
+        // that the cache doesn't grow beyond a screen.
+        let mut cache = self.segment_cache.borrow_mut();
+        if cache.query != self.get_query() {
+            cache.query = self.get_query().to_string();
+            cache.segments.clear();
+        }
+        let current_match = self
+            .current_match()
+            .map(|current| (current.line, current.range.clone()));
+        if cache.current_match != current_match {
+            // Only the lines with the old and the new current match look different.
+            let old_match = take(&mut cache.current_match);
+            for (line, _) in old_match.iter().chain(&current_match) {
+                cache.segments.remove(line);
+            }
+            cache.current_match = current_match;
+        }
+        let other_pane = self.other_pane();
+        cache.segments.retain(|idx, _| {
+            rows.contains(idx)
+                || other_pane
+                    .as_ref()
+                    .is_some_and(|(other_rows, ..)| other_rows.contains(idx))
+        });
+
+        // enqueue commands
+        out.queue(Hide)?;
+        if let Some((other_rows, top_row, height)) = other_pane {
+            self.queue_other_pane(out, &mut cache, other_rows, top_row, height)?;
+        }
+        out.queue(MoveTo(0, self.top_row as u16))?;
+        for (row, &idx) in rows.iter().enumerate() {
+            out.queue(Clear(ClearType::CurrentLine))?;
+            if self.line_numbers != LineNumbers::Hidden {
+                // The sticky header doesn't scroll, so its rows always have the absolute numbers.
+                // Otherwise only the first row of a source line is numbered.
+                let is_first_row = idx == 0 || self.source_lines[idx - 1] != self.source_lines[idx];
+                let number = match self.line_numbers {
+                    LineNumbers::Relative if row >= self.header_rows() => {
+                        Some(idx - self.current_top as usize)
+                    }
+                    _ if is_first_row && !self.is_separator_row(idx) => {
+                        Some(self.source_lines[idx] + 1)
+                    }
+                    _ => None,
+                };
+                queue_gutter(out, number)?;
+            }
+            let selected = self
+                .selection()
+                .is_some_and(|selection| selection.contains(&self.source_lines[idx]));
+            self.queue_row_text(out, &mut cache, idx, selected)?;
+            if self.label_mode {
+                let label = row
+                    .checked_sub(self.header_rows())
+                    .and_then(|pos| ROW_LABELS.chars().nth(pos));
+                if let Some(label) = label {
+                    queue!(
+                        out,
+                        MoveTo(0, (self.top_row + row) as u16),
+                        PrintStyledContent(label.reverse())
+                    )?;
+                }
+            }
+            self.queue_row_end(out, row)?;
+        }
+        drop(cache);
+
+        // Rows past the end of the contents are marked with `~` like less does. This also clears
+        // whatever was drawn there before.
+        for row in rows.len()..self.contents_height() {
+            queue!(out, Clear(ClearType::CurrentLine), Print('~'))?;
+            self.queue_row_end(out, row)?;
+        }
+
+        if self.show_scrollbar && self.body_height() > 0 {
+            let thumb = self.scrollbar_thumb();
+            for pos in 0..self.body_height() {
+                let row = (self.top_row + self.header_rows() + pos) as u16;
+                queue!(out, MoveTo(self.width.saturating_sub(1) as u16, row))?;
+                if thumb.contains(&pos) {
+                    out.queue(PrintStyledContent(' '.reverse()))?;
+                } else {
+                    out.queue(PrintStyledContent('│'.dim()))?;
+                }
+            }
+        }
+        if let Some((items, selected)) = &self.overlay {
+            self.queue_overlay(out, items, *selected)?;
+        }
+
+        let message = self.message.borrow().as_ref().cloned();
+        // The line being edited in the prompt, if any.
+        let editing = if self.query_mode {
+            Some(&self.query)
+        } else if self.command_mode {
+            Some(&self.command)
+        } else if self.filter_mode {
+            Some(&self.filter_input)
+        } else {
+            None
+        };
+        if !self.show_status_line && message.is_none() && editing.is_none() {
+            return self.finish_draw(out);
+        }
+
+        let prompt_row = (self.top_row + self.height).saturating_sub(1) as u16;
+        let editing = editing.filter(|_| message.is_none());
+        // The position among the matches follows the query, also while it is typed and the
+        // incremental search jumps to its first match.
+        let message = message.unwrap_or_else(|| match editing {
+            Some(line) if !self.query_mode => line.as_str().to_string(),
+            _ => match self.match_position() {
+                Some((pos, count)) => format!("{}  {}/{}", self.get_query(), pos, count),
+                None => self.get_query().to_string(),
+            },
+        });
+        queue!(
+            out,
+            MoveTo(0, prompt_row),
+            Clear(ClearType::CurrentLine),
+            Print(format_args!(
+                "{}{}",
+                if self.query_mode {
+                    '/'
+                } else if self.filter_mode {
+                    '&'
+                } else {
+                    ':'
+                },
+                message
+            )),
+        )?;
+
+        // The right side of the prompt shows where we are, or the spinner while loading.
+        let status = match self.loading {
+            _ if self.following => Some("(FOLLOW)".to_string()),
+            Some(frame) => {
+                const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+                Some(format!("loading... {}", SPINNER[frame % SPINNER.len()]))
+            }
+            None if self.is_at_end() => Some("(END)".to_string()),
+            None if self.current_top as usize <= self.header_rows() => Some("(TOP)".to_string()),
+            None => Some(format!("{}%", self.percentage())),
+        };
+        // The status is left out if it doesn't fit next to the `:`, e.g. on a tiny terminal.
+        if let Some(status) = status.filter(|status| status.len() < self.width) {
+            queue!(
+                out,
+                SavePosition,
+                MoveTo(self.width.saturating_sub(status.len()) as u16, prompt_row),
+                Print(status),
+                RestorePosition,
+            )?;
+        }
+
+        if let Some(line) = editing {
+            // Put the terminal cursor where the line is being edited, after the `/` or `:`.
+            let column = 1 + line.cursor_width();
+            out.queue(MoveTo(column as u16, prompt_row))?;
+        }
+
+        self.finish_draw(out)
+    }
+
+    /// Draws the row `idx` after the gutter: the wrap marker and the indentation of a continued
+    /// row, and the contents, which are highlighted if `selected`.
+    fn queue_row_text(
+        &self,
+        out: &mut impl Write,
+        cache: &mut SegmentCache,
+        idx: usize,
+        selected: bool,
+    ) -> io::Result<()> {
+        let indent = self.row_indents[idx];
+        if indent > 0 {
+            // The marker is left out if the indentation is cut too short for it.
+            let marker = self
+                .wrap_marker
+                .as_deref()
+                .filter(|marker| str_width(marker) <= indent)
+                .unwrap_or("");
+            let padding = " ".repeat(indent - str_width(marker));
+            queue!(out, PrintStyledContent(marker.dim()), Print(padding))?;
+        }
+        let segments = cache
+            .segments
+            .entry(idx)
+            .or_insert_with(|| self.line_segments(idx));
+        let clipped;
+        let segments = if self.wrap_mode == WrapMode::None {
+            clipped = clip_segments(segments, self.left_column, self.text_width());
+            &clipped
+        } else {
+            segments
+        };
+        for segment in segments.iter() {
+            let mut style = *segment.style();
+            if selected {
+                style.background_color = Some(Color::DarkGrey);
+            }
+            out.queue(PrintStyledContent(StyledContent::new(
+                style,
+                segment.content(),
+            )))?;
+        }
+
+        Ok(())
+    }
+
+    /// Draws the pane not focused, `height` rows from the terminal row `top_row`, with the `rows`
+    /// of the lines. Its last row tells which line is at its top instead of the prompt.
+    fn queue_other_pane(
+        &self,
+        out: &mut impl Write,
+        cache: &mut SegmentCache,
+        rows: Range<usize>,
+        top_row: usize,
+        height: usize,
+    ) -> io::Result<()> {
+        let start = rows.start;
+        let num_rows = rows.len();
+        out.queue(MoveTo(0, top_row as u16))?;
+        for idx in rows {
+            out.queue(Clear(ClearType::CurrentLine))?;
+            if self.line_numbers != LineNumbers::Hidden {
+                let is_first_row = idx == 0 || self.source_lines[idx - 1] != self.source_lines[idx];
+                let number = Some(self.source_lines[idx] + 1)
+                    .filter(|_| is_first_row && !self.is_separator_row(idx));
+                queue_gutter(out, number)?;
+            }
+            self.queue_row_text(out, cache, idx, false)?;
+            queue_newline(out)?;
+        }
+        for _ in num_rows..height.saturating_sub(1) {
+            queue!(out, Clear(ClearType::CurrentLine), Print('~'))?;
+            queue_newline(out)?;
+        }
+
+        let status = format!(
+            "line {}",
+            self.source_lines.get(start).map_or(1, |&line| line + 1)
+        );
+        let padding = " ".repeat(self.width.saturating_sub(status.len()));
+        queue!(
+            out,
+            MoveTo(0, (top_row + height).saturating_sub(1) as u16),
+            Clear(ClearType::CurrentLine),
+            PrintStyledContent(format!("{}{}", status, padding).reverse()),
+        )?;
+
+        Ok(())
+    }
+
+    /// Moves to the next row after drawing `row`. Nothing is output after the bottom row, or the
+    /// terminal would scroll.
+    fn queue_row_end(&self, out: &mut impl Write, row: usize) -> io::Result<()> {
+        if row + 1 < self.height {
+            queue_newline(out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Draws `items` in a box in the middle of the contents. If they don't fit, the ones around
+    /// `selected` are drawn.
+    fn queue_overlay(
+        &self,
+        out: &mut impl Write,
+        items: &[String],
+        selected: usize,
+    ) -> io::Result<()> {
+        let inner_width = items
+            .iter()
+            .map(|item| str_width(item))
+            .max()
+            .unwrap_or(0)
+            .min(self.width.saturating_sub(4));
+        let visible = items.len().min(self.contents_height().saturating_sub(2));
+        if inner_width == 0 || visible == 0 {
+            return Ok(());
+        }
+
+        let first = (selected + 1).saturating_sub(visible);
+        let left = ((self.width - inner_width - 4) / 2) as u16;
+        let top = self.top_row + (self.contents_height() - visible - 2) / 2;
+        let border =
+            |left: char, right: char| format!("{}{}{}", left, "─".repeat(inner_width + 2), right);
+        queue!(out, MoveTo(left, top as u16), Print(border('┌', '┐')))?;
+        for (pos, item) in items.iter().enumerate().skip(first).take(visible) {
+            let mut text = String::new();
+            let mut text_width = 0;
+            for ch in item.chars() {
+                let ch_width = ch.width().unwrap_or(0);
+                if text_width + ch_width > inner_width {
+                    break;
+                }
+                text.push(ch);
+                text_width += ch_width;
+            }
+            text.push_str(&" ".repeat(inner_width - text_width));
+            let text = format!(" {} ", text);
+            let row = (top + 1 + pos - first) as u16;
+            queue!(out, MoveTo(left, row), Print('│'))?;
+            if pos == selected {
+                out.queue(PrintStyledContent(text.reverse()))?;
+            } else {
+                out.queue(Print(text))?;
+            }
+            out.queue(Print('│'))?;
+        }
+        queue!(
+            out,
+            MoveTo(left, (top + visible + 1) as u16),
+            Print(border('└', '┘'))
+        )?;
+
+        Ok(())
+    }
+
+    fn finish_draw(&self, out: &mut impl Write) -> io::Result<()> {
+        out.queue(Show)?;
+
+        *self.message.borrow_mut() = None;
+        out.flush()?;
+
+        self.needs_update.set(false);
+
+        Ok(())
+    }
+
+    /// Splits the line into segments of the same style: search matches are highlighted, and
+    /// nonprinting characters and whitespace are replaced with visible markers if requested.
+    fn line_segments(&self, idx: usize) -> Vec<StyledContent<String>> {
+        if self.is_separator_row(idx) {
+            return vec!["--".to_string().dim()];
+        }
+
+        let line = &self.lines[idx];
+        let matches = match_ranges(line, self.query_regex().as_ref(), !self.show_nonprinting);
+        let current_match = self
+            .current_match()
+            .filter(|current| current.line == idx)
+            .map(|current| current.range.clone());
+
+        // Whitespace is only "trailing" at the end of the source line, not at a wrapping point.
+        let trailing_start = if self.show_nonprinting && self.ends_source_line(idx) {
+            line.trim_end_matches(char::is_whitespace).len()
+        } else {
+            line.len()
+        };
+
+        let mut segments = vec![];
+        let mut curr_style = None;
+        let mut text = String::new();
+        // Hyperlink sequences are printed in whatever style surrounds them; a style change in the
+        // middle would break the sequence.
+        let hyperlinks = if self.show_nonprinting {
+            vec![]
+        } else {
+            hyperlink_ranges(line)
+        };
+        // Of an overstruck character, only the last one is shown, in bold or underlined.
+        let overstrikes = if self.show_nonprinting {
+            vec![]
+        } else {
+            overstrike_ranges(line)
+        };
+        // Color sequences aren't printed as they are but change the style of the text after them,
+        // so that they don't get mixed up with the styles of the pager.
+        let sequences = if self.show_nonprinting {
+            vec![]
+        } else {
+            csi_ranges(line)
+        };
+        let mut sgr_style = ContentStyle::new();
+        // A tab reaches to the next tab stop, so its width depends on where it is.
+        let mut tab_widths = vec![];
+        if line.contains('\t') {
+            let mut column = 0;
+            for unit in display_units(line, !self.show_nonprinting) {
+                let width = marked_width(
+                    unit,
+                    column,
+                    self.tab_width,
+                    self.show_nonprinting,
+                    self.show_whitespace,
+                    self.raw_control_chars,
+                );
+                if unit == "\t" {
+                    tab_widths.push(width);
+                }
+                column += width;
+            }
+        }
+        let mut tab_widths = tab_widths.into_iter();
+        let mut matches = matches.into_iter().peekable();
+        let mut hyperlinks = hyperlinks.into_iter().peekable();
+        let mut overstrikes = overstrikes.into_iter().peekable();
+        let mut sequences = sequences.into_iter().peekable();
+        for (pos, ch) in line.char_indices() {
+            while sequences.peek().is_some_and(|range| range.end <= pos) {
+                sequences.next();
+            }
+            if let Some(range) = sequences.peek().filter(|range| range.contains(&pos)) {
+                if pos == range.start && is_sgr(&line[range.clone()]) {
+                    apply_sgr(&mut sgr_style, &line[range.clone()]);
+                }
+                continue;
+            }
+            while overstrikes
+                .peek()
+                .is_some_and(|(range, ..)| range.end <= pos)
+            {
+                overstrikes.next();
+            }
+            let overstrike = overstrikes
+                .peek()
+                .filter(|(range, ..)| range.contains(&pos))
+                .map(|&(_, shown_start, bold, underline)| (shown_start, bold, underline));
+            if overstrike.is_some_and(|(shown_start, ..)| pos < shown_start) {
+                continue;
+            }
+            while matches.peek().is_some_and(|range| range.end <= pos) {
+                matches.next();
+            }
+            while hyperlinks.peek().is_some_and(|range| range.end <= pos) {
+                hyperlinks.next();
+            }
+            let highlight = if hyperlinks.peek().is_some_and(|range| range.contains(&pos)) {
+                curr_style.map_or(Highlight::None, |(_, highlight, _)| highlight)
+            } else {
+                match matches.peek().filter(|range| range.contains(&pos)) {
+                    // The whole highlighted range around the current match is shown as current.
+                    Some(range)
+                        if current_match.as_ref().is_some_and(|current| {
+                            current.start < range.end && range.start < current.end
+                        }) =>
+                    {
+                        Highlight::Current
+                    }
+                    Some(_) => Highlight::Match,
+                    None => Highlight::None,
+                }
+            };
+            let class = if let Some((_, bold, underline)) = overstrike {
+                CharClass::Overstruck { bold, underline }
+            } else if self.show_whitespace && (ch == ' ' || ch == '\t') {
+                CharClass::Whitespace
+            } else if ch.is_control()
+                && (self.show_nonprinting || (ch != '\t' && !self.raw_control_chars))
+            {
+                CharClass::Control
+            } else if !self.show_nonprinting {
+                CharClass::Normal
+            } else if pos >= trailing_start {
+                CharClass::TrailingSpace
+            } else {
+                CharClass::Normal
+            };
+
+            if curr_style != Some((class, highlight, sgr_style)) {
+                if let Some((class, highlight, base)) = curr_style {
+                    segments.push(style_segment(
+                        take(&mut text),
+                        base,
+                        class,
+                        highlight,
+                        self.highlight_color,
+                    ));
+                }
+                curr_style = Some((class, highlight, sgr_style));
+            }
+
+            match class {
+                CharClass::Normal if ch == '\t' => {
+                    text.push_str(&" ".repeat(tab_widths.next().unwrap_or(1)));
+                }
+                CharClass::Normal | CharClass::Overstruck { .. } => text.push(ch),
+                CharClass::Control => text.push_str(&caret_notation(ch)),
+                CharClass::TrailingSpace => text.push_str(&"·".repeat(ch.width().unwrap_or(1))),
+                CharClass::Whitespace if ch == '\t' => {
+                    text.push('→');
+                    text.push_str(&" ".repeat(tab_widths.next().unwrap_or(1) - 1));
+                }
+                CharClass::Whitespace => text.push('·'),
+            }
+        }
+
+        if let Some((class, highlight, base)) = curr_style {
+            segments.push(style_segment(
+                text,
+                base,
+                class,
+                highlight,
+                self.highlight_color,
+            ));
+        }
+
+        let is_last_row = self.source_lines.get(idx + 1) != Some(&self.source_lines[idx])
+            || self.is_separator_row(idx + 1);
+        if let Some(marker) = self.wide_gap_marker {
+            // Only a character wider than the rest of the row makes a gap when lines are broken at
+            // any character.
+            let row_width: usize = segments.iter().map(|s| str_width(s.content())).sum();
+            let width = self.text_width() - self.row_indents[idx];
+            if self.wrap_mode == WrapMode::Char && !is_last_row && row_width < width {
+                segments.push(marker.to_string().repeat(width - row_width).dim());
+            }
+        }
+        if self.show_whitespace && is_last_row {
+            segments.push(style_segment(
+                "$".to_string(),
+                ContentStyle::new(),
+                CharClass::Whitespace,
+                Highlight::None,
+                self.highlight_color,
+            ));
+        }
+
+        segments
+    }
+
+    /// Returns whether the rest of the source line after the wrapped line `idx` is blank, i.e. any
+    /// whitespace at the end of `idx` is trailing whitespace of the source line.
+    fn ends_source_line(&self, idx: usize) -> bool {
+        let source_line = self.source_lines[idx];
+        self.lines[idx + 1..]
+            .iter()
+            .zip(&self.source_lines[idx + 1..])
+            .take_while(|(_, &source)| source == source_line)
+            .all(|(line, _)| line.trim().is_empty())
+    }
+
+    /// Returns whether the row `idx` separates the groups of lines shown by the filter.
+    fn is_separator_row(&self, idx: usize) -> bool {
+        self.separator_rows.binary_search(&idx).is_ok()
+    }
+
+    /// Returns the number of columns the lines are wrapped in, leaving room for the line numbers
+    /// and the scrollbar.
+    fn text_width(&self) -> usize {
+        let mut width = self.width;
+        if self.line_numbers != LineNumbers::Hidden {
+            width = width.saturating_sub(LINE_NUMBER_WIDTH + 1);
+        }
+        if self.show_scrollbar {
+            width = width.saturating_sub(1);
+        }
+
+        width.max(1)
+    }
+
+    /// Returns the number of screen lines of the whole contents. Until everything is wrapped, it is
+    /// estimated from how many lines the part wrapped so far makes.
+    fn estimated_rows(&self) -> usize {
+        if self.wrapped_all || self.last_line_start == 0 {
+            return self.lines.len();
+        }
+
+        let estimate =
+            self.lines.len() as u128 * self.contents.len() as u128 / self.last_line_start as u128;
+        max(estimate as usize, self.lines.len())
+    }
+
+    /// Returns how far the bottom of the screen is in the contents, in percent. Until everything is
+    /// wrapped, it is estimated like `estimated_rows()`.
+    fn percentage(&self) -> usize {
+        let bottom = self.current_top as usize + self.body_height();
+        min(bottom * 100 / self.estimated_rows().max(1), 100)
+    }
+
+    /// Returns the rows of the body which the thumb of the scrollbar takes, i.e. where the screen
+    /// is in the contents and how much of it is shown.
+    fn scrollbar_thumb(&self) -> Range<usize> {
+        let body_height = self.body_height();
+        // There is no room for the scrollbar on a screen with only the prompt row.
+        if body_height == 0 {
+            return 0..0;
+        }
+        let scrollable_rows = self.estimated_rows().saturating_sub(self.header_rows());
+        if scrollable_rows <= body_height {
+            return 0..body_height;
+        }
+
+        let len = (body_height * body_height / scrollable_rows).max(1);
+        let top = (self.current_top as usize).saturating_sub(self.header_rows());
+        let start = min(top * body_height / scrollable_rows, body_height - len);
+        start..start + len
+    }
+
+    fn contents_height(&self) -> usize {
+        // The last line is for prompt `:`
+        if self.show_status_line {
+            self.height.saturating_sub(1)
+        } else {
+            self.height
+        }
+    }
+
+    /// Returns the selected range of source lines, sorted. Until the end is set, only the start
+    /// line is selected.
+    fn selection(&self) -> Option<RangeInclusive<usize>> {
+        let start = self.selection_start?;
+        let end = self.selection_end.unwrap_or(start);
+        Some(min(start, end)..=max(start, end))
+    }
+
+    /// Returns the line a search starts from: the last match if we are still looking at it, or the
+    /// top line otherwise.
+    fn search_origin(&self) -> usize {
+        match self.search_cursor() {
+            Some((line, _)) => line,
+            None => self.current_top as usize,
+        }
+    }
+
+    /// Returns the last match jumped to, if we are still looking at it.
+    fn current_match(&self) -> Option<&Match> {
+        self.last_match
+            .as_ref()
+            .filter(|last_match| last_match.top == self.current_top)
+    }
+
+    /// Returns the line and the byte offset in it of the match a search continues from.
+    fn search_cursor(&self) -> Option<(usize, usize)> {
+        self.current_match()
+            .map(|current| (current.line, current.range.start))
+    }
+
+    /// Returns the (1-based) position of the match last jumped to among all the matches, and the
+    /// number of the matches. `None` if the screen has been scrolled away since the jump.
+    fn match_position(&self) -> Option<(usize, usize)> {
+        let cursor = self.search_cursor()?;
+        let regex = self.query_regex()?;
+
+        let mut match_positions = self.match_positions.borrow_mut();
+        let is_stale = match &*match_positions {
+            Some((query, len, _)) => query != self.get_query() || *len != self.lines.len(),
+            None => true,
+        };
+        if is_stale {
+            let positions = self
+                .lines
+                .iter()
+                .enumerate()
+                .flat_map(|(idx, line)| {
+                    self.find_matches(line, &regex)
+                        .into_iter()
+                        .map(move |range| (idx, range.start))
+                })
+                .collect();
+            *match_positions = Some((self.get_query().to_string(), self.lines.len(), positions));
+        }
+
+        let (_, _, positions) = match_positions.as_ref().unwrap();
+        let pos = positions.binary_search(&cursor).ok()?;
+        Some((pos + 1, positions.len()))
+    }
+
+    /// Scrolls to the match at `range` in `line`, keeping `scrolloff` lines above it. The margin is
+    /// limited to half of the screen so that the line is always visible.
+    fn jump_to(&mut self, line: usize, range: Range<usize>) {
+        self.note_jump();
+        let scrolloff = min(self.scrolloff, self.body_height().saturating_sub(1) / 2);
+        self.current_top = line.saturating_sub(scrolloff) as isize;
+        self.fix_current_top();
+        self.last_match = Some(Match {
+            line,
+            range,
+            top: self.current_top,
+        });
+        self.needs_update.set(true);
+    }
+
+    fn scroll_amount(&self, unit: MoveUnit, count: usize) -> isize {
+        let contents_height = self.body_height();
+        let count = isize::try_from(count).unwrap_or(isize::MAX);
+        let amount = match unit {
+            MoveUnit::Line => self.line_amount.unwrap_or(1),
+            MoveUnit::HalfPage => self
+                .half_page_amount
+                .unwrap_or_else(|| (contents_height / 2).max(1)),
+            MoveUnit::Page => self
+                .page_amount
+                .unwrap_or_else(|| contents_height.saturating_sub(self.page_overlap).max(1)),
+            MoveUnit::Entire => return isize::MAX,
+        };
+        let amount = isize::try_from(amount).unwrap_or(isize::MAX);
+
+        amount.saturating_mul(count)
+    }
+
+    /// Throws away the wrapped lines, e.g. after the width has changed. They are wrapped again
+    /// when needed, usually by `fix_current_top()`.
+    fn recalc_lines(&mut self) {
+        self.lines.clear();
+        self.source_lines.clear();
+        self.row_indents.clear();
+        self.separator_rows.clear();
+        if let Some(filter) = &mut self.filter {
+            filter.reset();
+        }
+        self.last_line_start = 0;
+        self.last_source_line = 0;
+        self.wrapped_all = false;
+        self.segment_cache.get_mut().segments.clear();
+        *self.match_positions.get_mut() = None;
+        self.needs_update.set(true);
+    }
+
+    /// Wraps more of the contents until there are more than `rows` lines or everything is
+    /// wrapped. Wrapping stops only between source lines, so it can continue from there.
+    fn wrap_until(&mut self, rows: usize) {
+        if self.wrapped_all {
+            return;
+        }
+
+        let start = self.last_line_start;
+        let source_line = self.last_source_line;
+        let mut breaker = LineBreaker::new(self.text_width(), &self.contents[start..]);
+        let mut filtered_out = false;
+        breaker.set_tab_width(self.tab_width);
+        breaker.set_show_nonprinting(self.show_nonprinting);
+        breaker.set_raw_control_chars(self.raw_control_chars);
+        breaker.set_show_whitespace(self.show_whitespace);
+        breaker.set_cr_mode(self.cr_mode);
+        breaker.set_wrap_mode(self.wrap_mode);
+        breaker.set_max_rows(self.max_rows);
+        breaker.set_wrap_marker_width(self.wrap_marker.as_deref().map_or(0, str_width));
+        breaker.set_keep_indent(self.keep_indent);
+        loop {
+            if self.lines.len() > rows && breaker.is_at_line_boundary() {
+                self.last_line_start = start + breaker.line_start();
+                self.last_source_line = source_line + breaker.source_line();
+                return;
+            }
+
+            let curr_source_line = source_line + breaker.source_line();
+            if let Some(filter) = self
+                .filter
+                .as_mut()
+                .filter(|_| breaker.is_at_line_boundary())
+            {
+                let last_shown = filter.last_shown;
+                let rest = &self.contents[start + breaker.line_start()..];
+                filtered_out = !filter.shows(curr_source_line, rest, self.cr_mode);
+                // The separator belongs to the line before it, so that wrapping the last line
+                // again keeps it.
+                if let Some(last_shown) = last_shown {
+                    if !filtered_out && filter.context > 0 && last_shown + 1 < curr_source_line {
+                        self.separator_rows.push(self.lines.len());
+                        self.lines.push(String::new());
+                        self.source_lines.push(last_shown);
+                        self.row_indents.push(0);
+                    }
+                }
+            }
+            let line = match breaker.next() {
+                Some(line) => line,
+                None => break,
+            };
+            if filtered_out {
+                continue;
+            }
+            if self.squeeze_blank_lines && self.follows_blank_line(&line, curr_source_line) {
+                continue;
+            }
+            self.lines.push(line);
+            self.source_lines.push(curr_source_line);
+            self.row_indents.push(breaker.row_indent());
+        }
+        self.last_line_start = start + breaker.line_start();
+        self.last_source_line = source_line + breaker.source_line();
+        self.wrapped_all = true;
+    }
+
+    /// Wraps the contents at least up to the source line `source_line`.
+    fn wrap_through_source_line(&mut self, source_line: usize) {
+        while !self.wrapped_all && self.last_source_line <= source_line {
+            self.wrap_until(self.lines.len() + self.contents_height());
+        }
+    }
+
+    /// Returns whether `line` of the source line `source_line` is blank and comes right after
+    /// another blank source line, so that it can be squeezed out.
+    fn follows_blank_line(&self, line: &str, source_line: usize) -> bool {
+        let is_blank = |line: &str| line.trim().is_empty();
+        is_blank(line)
+            && self.source_lines.last() != Some(&source_line)
+            && self.lines.last().is_some_and(|last| is_blank(last))
+    }
+
+    fn scroll(&mut self, amount: isize) {
+        self.current_top = self.current_top.saturating_add(amount);
+        self.fix_current_top();
+        self.needs_update.set(true);
+    }
+
+    /// Returns the number of screen lines the sticky header takes. At least one line is left for
+    /// scrolling.
+    fn header_rows(&self) -> usize {
+        let rows = self
+            .source_lines
+            .partition_point(|&source_line| source_line < self.sticky_header);
+        min(rows, self.contents_height().saturating_sub(1))
+    }
+
+    /// Returns the height of the area below the sticky header.
+    fn body_height(&self) -> usize {
+        self.contents_height() - self.header_rows()
+    }
+
+    /// Returns the range of the lines shown below the sticky header.
+    fn body_range(&self) -> Range<usize> {
+        let start = self.current_top as usize;
+        start..min(self.lines.len(), start + self.body_height())
+    }
+
+    /// Returns the rows shown in the pane not focused, the terminal row where it starts and its
+    /// height, while the screen is split. Its last row is the status line.
+    fn other_pane(&self) -> Option<(Range<usize>, usize, usize)> {
+        let split = self.split.as_ref()?;
+        let height = self.screen_height.saturating_sub(self.height);
+        let top_row = if split.focus_upper { self.height } else { 0 };
+        let start = self.source_lines.partition_point(|&l| l < split.top_line);
+        let end = min(self.lines.len(), start + height.saturating_sub(1));
+        Some((start..end, top_row, height))
+    }
+
+    /// Returns the largest `current_top`, where the last line is at the bottom of the screen.
+    fn max_top(&self) -> usize {
+        self.lines
+            .len()
+            .saturating_sub(self.body_height())
+            .max(self.header_rows())
+    }
+
+    fn fix_current_top(&mut self) {
+        // Wrap the lines up to the bottom of the screen, and one more to tell whether it is the
+        // end.
+        let bottom = (self.current_top.max(0) as usize).saturating_add(self.contents_height());
+        self.wrap_until(bottom);
+        if let Some(top_line) = self.split.as_ref().map(|split| split.top_line) {
+            self.wrap_through_source_line(top_line);
+            let top = self.source_lines.partition_point(|&l| l < top_line);
+            self.wrap_until(top + self.screen_height);
+        }
+        self.current_top = self
+            .current_top
+            .clamp(self.header_rows() as isize, self.max_top() as isize);
+        self.needs_update.set(true);
+    }
+}
+
+/// Returns when the file at `path` was last modified, if the platform tells.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Draws the gutter of a row, with the line `number` if any.
+fn queue_gutter(out: &mut impl Write, number: Option<usize>) -> io::Result<()> {
+    let gutter = match number {
+        Some(number) => format!("{:>width$} ", number, width = LINE_NUMBER_WIDTH),
+        None => " ".repeat(LINE_NUMBER_WIDTH + 1),
+    };
+    out.queue(PrintStyledContent(gutter.dim()))?;
+
+    Ok(())
+}
+
+fn queue_newline(out: &mut impl Write) -> io::Result<()> {
+    // seems bit flicker-less (why?)
+    if cfg!(windows) {
+        out.queue(Print('\n'))?;
+    } else {
+        out.queue(Print("\r\n"))?;
+    }
+
+    Ok(())
+}
+
+/// The way a character is displayed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Normal,
+    Control,
+    TrailingSpace,
+    /// A space or a tab marked while whitespace is shown, or the marker of a line end.
+    Whitespace,
+    /// A character overstruck with backspaces, as in man pages.
+    Overstruck {
+        bold: bool,
+        underline: bool,
+    },
+}
+
+fn style_segment(
+    text: String,
+    base: ContentStyle,
+    class: CharClass,
+    highlight: Highlight,
+    highlight_color: Color,
+) -> StyledContent<String> {
+    let mut segment = StyledContent::new(base, text);
+    match highlight {
+        Highlight::None => {}
+        Highlight::Match => segment = segment.with(highlight_color),
+        // The current match stands out from the others in the reversed color.
+        Highlight::Current => segment = segment.with(highlight_color).reverse(),
+    }
+
+    match class {
+        CharClass::Normal => segment,
+        CharClass::Control => segment.reverse(),
+        CharClass::TrailingSpace | CharClass::Whitespace => segment.dim(),
+        CharClass::Overstruck { bold, underline } => {
+            if bold {
+                segment = segment.bold();
+            }
+            if underline {
+                segment = segment.underlined();
+            }
+            segment
+        }
+    }
+}
+
+/// Applies the SGR escape sequence `seq` (e.g. `\x1b[1;31m`) to `style`. Unknown parameters are
+/// ignored, and so are the ones which can't be parsed, e.g. `38:5:208` with colons.
+fn apply_sgr(style: &mut ContentStyle, seq: &str) {
+    // An empty parameter means 0, e.g. in `\x1b[m`.
+    let params: Vec<_> = seq[2..seq.len() - 1]
+        .split(';')
+        .map(|param| match param {
+            "" => Some(0),
+            _ => param.parse::<u8>().ok(),
+        })
+        .collect();
+    let mut params = params.into_iter();
+    while let Some(param) = params.next() {
+        let param = match param {
+            Some(param) => param,
+            None => continue,
+        };
+        let mut next_param = || params.next().flatten();
+        match param {
+            0 => *style = ContentStyle::new(),
+            1 => style.attributes.set(Attribute::Bold),
+            2 => style.attributes.set(Attribute::Dim),
+            3 => style.attributes.set(Attribute::Italic),
+            4 => style.attributes.set(Attribute::Underlined),
+            5 => style.attributes.set(Attribute::SlowBlink),
+            7 => style.attributes.set(Attribute::Reverse),
+            8 => style.attributes.set(Attribute::Hidden),
+            9 => style.attributes.set(Attribute::CrossedOut),
+            22 => {
+                style.attributes.unset(Attribute::Bold);
+                style.attributes.unset(Attribute::Dim);
+            }
+            23 => style.attributes.unset(Attribute::Italic),
+            24 => style.attributes.unset(Attribute::Underlined),
+            25 => style.attributes.unset(Attribute::SlowBlink),
+            27 => style.attributes.unset(Attribute::Reverse),
+            28 => style.attributes.unset(Attribute::Hidden),
+            29 => style.attributes.unset(Attribute::CrossedOut),
+            30..=37 => style.foreground_color = Some(Color::AnsiValue(param - 30)),
+            39 => style.foreground_color = None,
+            40..=47 => style.background_color = Some(Color::AnsiValue(param - 40)),
+            49 => style.background_color = None,
+            90..=97 => style.foreground_color = Some(Color::AnsiValue(param - 90 + 8)),
+            100..=107 => style.background_color = Some(Color::AnsiValue(param - 100 + 8)),
+            38 | 48 => {
+                let color = match next_param() {
+                    Some(5) => next_param().map(Color::AnsiValue),
+                    Some(2) => match (next_param(), next_param(), next_param()) {
+                        (Some(r), Some(g), Some(b)) => Some(Color::Rgb { r, g, b }),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if param == 38 {
+                    style.foreground_color = color;
+                } else {
+                    style.background_color = color;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Cuts out the columns `left..left + width` of a line made of `segments`. If the line continues
+/// beyond the left or the right edge, a reversed `<` or `>` is put in that column instead.
+fn clip_segments(
+    segments: &[StyledContent<String>],
+    left: usize,
+    width: usize,
+) -> Vec<StyledContent<String>> {
+    let line_width: usize = segments
+        .iter()
+        .map(|segment| str_width(segment.content()))
+        .sum();
+    let has_left = left > 0 && line_width > 0;
+    let has_right = width >= 2 && line_width > left + width;
+    let start = left + has_left as usize;
+    let end = max(left + width - has_right as usize, start);
+
+    let mut clipped = vec![];
+    if has_left {
+        clipped.push("<".to_string().reverse());
+    }
+    let mut column = 0;
+    for segment in segments {
+        let mut text = String::new();
+        for cluster in display_units(segment.content(), true) {
+            let (from, to) = (column, column + cluster_width(cluster));
+            column = to;
+            // Hyperlinks are kept even out of the screen, so that the visible part of a link
+            // still works.
+            if is_hyperlink(cluster) {
+                text.push_str(cluster);
+                continue;
+            }
+            if to <= start || from >= end {
+                continue;
+            }
+
+            if from < start || to > end {
+                // Only a part of a wide character is on the screen; fill that part with spaces.
+                text.push_str(&" ".repeat(min(to, end) - max(from, start)));
+            } else {
+                text.push_str(cluster);
+            }
+        }
+        if !text.is_empty() {
+            clipped.push(StyledContent::new(*segment.style(), text));
+        }
+    }
+    if has_right {
+        clipped.push(">".to_string().reverse());
+    }
+
+    clipped
+}
+
+/// Finds the occurrences of `regex` in `line` in order, which don't overlap like `find_iter()`.
+/// These are the matches the search goes through and counts.
+fn find_matches(line: &str, regex: &Regex, sequences: bool) -> Vec<Range<usize>> {
+    find_in_shown_text(line, sequences, |text| {
+        regex.find_iter(text).map(|found| found.range()).collect()
+    })
+}
+
+/// Finds all the occurrences of `regex` in `line`, in order. Unlike `find_matches()`, occurrences
+/// overlapping the previous one are also found, e.g. two `aa` in `aaa`, so that all of them are
+/// highlighted.
+fn find_overlapping_matches(line: &str, regex: &Regex, sequences: bool) -> Vec<Range<usize>> {
+    find_in_shown_text(line, sequences, |text| find_overlapping_in(text, regex))
+}
+
+/// Runs `find` on `line` as it is shown, i.e. through the escape sequences and overstruck
+/// characters if `sequences`, and maps the ranges found back to the bytes of `line`.
+fn find_in_shown_text(
+    line: &str,
+    sequences: bool,
+    find: impl Fn(&str) -> Vec<Range<usize>>,
+) -> Vec<Range<usize>> {
+    if !sequences || !line.contains(['\x1b', '\x08']) {
+        return find(line);
+    }
+
+    // The matches in the shown text are mapped back to the bytes of `line` they are shown from.
+    let (text, offsets) = shown_text(line);
+    find(&text)
+        .into_iter()
+        .map(|range| offsets[range.start]..offsets[range.end])
+        .collect()
+}
+
+fn find_overlapping_in(line: &str, regex: &Regex) -> Vec<Range<usize>> {
+    let mut matches = vec![];
+    let mut search_from = 0;
+    while search_from <= line.len() {
+        let found = match regex.find_at(line, search_from) {
+            Some(found) => found.range(),
+            None => break,
+        };
+        search_from = found.start + line[found.start..].chars().next().map_or(1, char::len_utf8);
+        matches.push(found);
+    }
+
+    matches
+}
+
+/// Finds the byte ranges to highlight for the query `regex` in `line`, sorted and disjoint.
+/// Overlapping or adjacent occurrences are merged into one range, so `aa` in `aaaaa` highlights the
+/// whole line. An empty query (`None`) or an empty match, e.g. of `^`, highlights nothing.
+///
+/// Each range is widened to whole grapheme clusters as they appear on the terminal: combining
+/// accents or the members of an emoji sequence are drawn together with their base character, so
+/// they must share its style or the highlight looks misplaced.
+fn match_ranges(line: &str, regex: Option<&Regex>, sequences: bool) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    let regex = match regex {
+        Some(regex) => regex,
+        None => return ranges,
+    };
+
+    let boundaries: Vec<_> = line
+        .grapheme_indices(true)
+        .map(|(idx, _)| idx)
+        .chain(Some(line.len()))
+        .collect();
+
+    for found in find_overlapping_matches(line, regex, sequences) {
+        if found.is_empty() {
+            continue;
+        }
+
+        // Move each end outwards to the nearest cluster boundary.
+        let start = boundaries[boundaries.partition_point(|&idx| idx <= found.start) - 1];
+        let end = boundaries[boundaries.partition_point(|&idx| idx < found.end)];
+
+        match ranges.last_mut() {
+            Some(last) if last.end >= start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(scr: &Screen) -> String {
+        let mut out = vec![];
+        scr.draw(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn draw_on_one_row() {
+        let mut scr = Screen::new(10, 1, "line 1\nline 2\n".to_string());
+        scr.toggle_scrollbar();
+        for _ in 0..2 {
+            // Only the prompt is drawn, on the first row.
+            let out = draw(&scr);
+            assert!(out.contains("\x1b[1;1H\x1b[2K:"));
+            assert!(!out.contains("line"));
+            assert!(!out.contains('│'));
+            assert!(!out.contains('\n'));
+            scr.down_by(MoveUnit::Page, 1);
+        }
+    }
+
+    /// Returns the 1-based rows and columns which the cursor is moved to in `out`.
+    fn cursor_moves(out: &str) -> Vec<(usize, usize)> {
+        Regex::new(r"\x1b\[(\d+);(\d+)H")
+            .unwrap()
+            .captures_iter(out)
+            .map(|caps| (caps[1].parse().unwrap(), caps[2].parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn draw_on_zero_size() {
+        let mut scr = Screen::new(0, 0, "line 1\nline 2\n".to_string());
+        scr.toggle_scrollbar();
+        scr.update_size(0, 0);
+        // The screen is one cell, which only has room for the `:`.
+        let out = draw(&scr);
+        assert!(cursor_moves(&out)
+            .iter()
+            .all(|&position| position == (1, 1)));
+        assert!(out.contains("\x1b[2K:\x1b[?25h"));
+        assert!(!out.contains('\n'));
+    }
+
+    #[test]
+    fn count_matches_before_wrapping_all() {
+        let contents: String = (1..=1000).map(|n| format!("hit {}\n", n)).collect();
+        let mut scr = Screen::new(80, 10, contents);
+        scr.goto_line(100);
+        assert!(!scr.wrapped_all);
+
+        scr.get_query_mut().set("hit".to_string());
+        scr.prev();
+        let line = scr.last_match.as_ref().unwrap().line;
+        assert_eq!(scr.match_position(), Some((line + 1, 1000)));
+    }
+
+    #[test]
+    fn dump_input_without_file() {
+        let mut scr = Screen::new(80, 10, "0123456789abcdef01".to_string());
+        scr.toggle_hex_dump();
+        assert_eq!(scr.contents, hex_dump(b"0123456789abcdef01"));
+
+        // The short last line is made again with the bytes read later.
+        scr.down_by(MoveUnit::Entire, 1);
+        scr.append("23456789abcdef\0x");
+        let text = "0123456789abcdef0123456789abcdef\0x";
+        assert_eq!(scr.contents, hex_dump(text.as_bytes()));
+        scr.down_by(MoveUnit::Entire, 1);
+        assert_eq!(scr.lines, scr.contents.lines().collect::<Vec<_>>());
+
+        scr.toggle_hex_dump();
+        assert_eq!(scr.contents, text);
+    }
+
+    #[test]
+    fn draw_highlighted_matches() {
+        let mut scr = Screen::new(20, 3, "foo bar baz\n".to_string());
+        scr.get_query_mut().set("ba.".to_string());
+        let red = "\x1b[38;5;9m";
+        let reset = "\x1b[39m";
+        let first_row = |scr: &Screen| {
+            let newline = if cfg!(windows) { "\n" } else { "\r\n" };
+            draw(scr).split(newline).next().unwrap().to_string()
+        };
+        assert_eq!(
+            first_row(&scr),
+            format!(
+                "\x1b[?25l\x1b[1;1H\x1b[2Kfoo {}bar{} {}baz{}",
+                red, reset, red, reset
+            )
+        );
+
+        // The current match is reversed.
+        scr.next();
+        assert_eq!(
+            first_row(&scr),
+            format!(
+                "\x1b[?25l\x1b[1;1H\x1b[2Kfoo {}\x1b[7mbar\x1b[0m {}baz{}",
+                red, red, reset
+            )
+        );
+    }
+
+    #[test]
+    fn mark_rows_past_end() {
+        let mut scr = Screen::new(10, 6, "line 1\nline 2\nline 3\n".to_string());
+        scr.down_by(MoveUnit::Entire, 1);
+        let out = draw(&scr);
+        let clear = "\x1b[2K";
+        let newline = if cfg!(windows) { "\n" } else { "\r\n" };
+        let rows: Vec<_> = out.split(newline).collect();
+        assert!(rows[0].ends_with(&format!("{}line 1", clear)));
+        assert_eq!(
+            &rows[1..3],
+            [format!("{}line 2", clear), format!("{}line 3", clear)]
+        );
+        // The two rows left above the prompt are cleared and marked.
+        assert_eq!(rows[3], format!("{}~", clear));
+        assert!(rows[4].starts_with(&format!("{}~", clear)));
+    }
+
+    #[test]
+    fn squeeze_blank_lines() {
+        let mut scr = Screen::new(10, 20, "a\n\n\n\nb\n\nc\n\n\n".to_string());
+        scr.set_squeeze_blank_lines(true);
+        assert_eq!(scr.lines, ["a", "", "b", "", "c", ""]);
+        // The line numbers still count the blank lines squeezed out.
+        assert_eq!(scr.source_lines, [0, 1, 4, 5, 6, 7]);
+
+        scr.set_squeeze_blank_lines(false);
+        assert_eq!(scr.lines, ["a", "", "", "", "b", "", "c", "", ""]);
+    }
+
+    /// Returns the text of the wrapped line `idx` as it is drawn.
+    fn row_text(scr: &Screen, idx: usize) -> String {
+        scr.line_segments(idx)
+            .iter()
+            .map(|segment| segment.content().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn fill_gaps_left_by_wide_characters() {
+        let mut scr = Screen::new(7, 10, "あいうえおかきくけこさ\naあいう\n".to_string());
+        assert_eq!(scr.lines, ["あいう", "えおか", "きくけ", "こさ", "aあいう"]);
+        assert_eq!(row_text(&scr, 0), "あいう");
+
+        scr.set_wide_gap_marker(Some('>'));
+        let rows: Vec<_> = (0..scr.lines.len())
+            .map(|idx| row_text(&scr, idx))
+            .collect();
+        // The rows but the last of each source line reach the right edge.
+        assert_eq!(rows, ["あいう>", "えおか>", "きくけ>", "こさ", "aあいう"]);
+        assert!(rows[..3].iter().all(|row| str_width(row) == 7));
+    }
+
+    #[test]
+    fn style_overstruck_characters() {
+        let scr = Screen::new(20, 10, "B\x08Bo\x08ox _\x08u\n".to_string());
+        let segments: Vec<_> = scr
+            .line_segments(0)
+            .into_iter()
+            .map(|segment| {
+                let attributes = segment.style().attributes;
+                (
+                    segment.content().clone(),
+                    attributes.has(Attribute::Bold),
+                    attributes.has(Attribute::Underlined),
+                )
+            })
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                ("Bo".to_string(), true, false),
+                ("x ".to_string(), false, false),
+                ("u".to_string(), false, true),
+            ]
+        );
+    }
+
+    /// Returns the ranges to highlight as pairs, which are easier to compare.
+    fn ranges(line: &str, query: &str) -> Vec<(usize, usize)> {
+        match_ranges(line, Some(&Regex::new(query).unwrap()), true)
+            .into_iter()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    /// Returns the text of each segment of the wrapped line `idx`, and whether it is highlighted as
+    /// a match.
+    fn highlighted_segments(scr: &Screen, idx: usize) -> Vec<(String, bool)> {
+        scr.line_segments(idx)
+            .into_iter()
+            .map(|segment| {
+                let highlighted = segment.style().foreground_color == Some(Color::Red);
+                (segment.content().clone(), highlighted)
+            })
+            .collect()
+    }
+
+    fn assert_highlighted(scr: &Screen, idx: usize, expected: &[(&str, bool)]) {
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(text, highlighted)| (text.to_string(), highlighted))
+            .collect();
+        assert_eq!(highlighted_segments(scr, idx), expected);
+    }
+
+    #[test]
+    fn highlight_overlapping_matches_as_one_segment() {
+        let mut scr = Screen::new(20, 10, "aaaaa\nabab\nxabab ab\n".to_string());
+        scr.get_query_mut().set("aa".to_string());
+        assert_highlighted(&scr, 0, &[("aaaaa", true)]);
+
+        // Adjacent matches are highlighted together, too.
+        scr.get_query_mut().set("ab".to_string());
+        assert_highlighted(&scr, 1, &[("abab", true)]);
+        let expected = [("x", false), ("abab", true), (" ", false), ("ab", true)];
+        assert_highlighted(&scr, 2, &expected);
+        for idx in 0..3 {
+            assert!(highlighted_segments(&scr, idx)
+                .iter()
+                .all(|(text, _)| !text.is_empty()));
+        }
+    }
+
+    #[test]
+    fn merge_overlapping_matches() {
+        assert_eq!(ranges("aaaaa", "aa"), [(0, 5)]);
+        assert_eq!(ranges("abab", "ab"), [(0, 4)]);
+        assert_eq!(ranges("ab ab", "ab"), [(0, 2), (3, 5)]);
+        assert_eq!(ranges("xaaax", "aa"), [(1, 4)]);
+    }
+
+    #[test]
+    fn skip_empty_matches() {
+        assert!(ranges("abc", "^").is_empty());
+        assert!(ranges("abc", "x*").is_empty());
+        assert!(match_ranges("abc", None, true).is_empty());
+    }
+
+    #[test]
+    fn match_wide_and_combining_characters() {
+        // Each of `日本語` takes 3 bytes.
+        assert_eq!(ranges("日本語 abc", "本"), [(3, 6)]);
+        assert_eq!(ranges("日本語 abc", "語 a"), [(6, 11)]);
+        // A match of the base character covers its combining accent too.
+        assert_eq!(ranges("cafe\u{301} ok", "e"), [(3, 6)]);
+        assert_eq!(ranges("cafe\u{301} ok", "\u{301}"), [(3, 6)]);
+    }
+
+    #[test]
+    fn highlight_segments_of_wide_and_combining_characters() {
+        let mut scr = Screen::new(40, 10, "日本語 cafe\u{301}!\n".to_string());
+        // The match ends at `e`, but its accent is highlighted with it.
+        scr.get_query_mut().set("語 cafe".to_string());
+        let expected = [("日本", false), ("語 cafe\u{301}", true), ("!", false)];
+        assert_highlighted(&scr, 0, &expected);
+    }
+}
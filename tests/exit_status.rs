@@ -0,0 +1,34 @@
+use std::process::{Command, Stdio};
+
+fn pag() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_pag"))
+}
+
+#[test]
+fn fail_on_missing_file() {
+    let output = pag()
+        .arg("no/such/file")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("(error: failed to read `no/such/file`: "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn fail_on_empty_input() {
+    let output = pag().stdin(Stdio::null()).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "(error: input was empty)\n"
+    );
+}